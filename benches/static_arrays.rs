@@ -1,5 +1,6 @@
 use criterion::measurement::WallTime;
-use criterion::{black_box, criterion_group, criterion_main, BenchmarkGroup, Criterion};
+use criterion::{black_box, BenchmarkGroup, Criterion};
+use trait_vec::poly_push;
 use trait_vec::trait_vec::PolyPtrVec;
 
 fn bench_normal_vec(c: &mut BenchmarkGroup<WallTime>) {
@@ -58,18 +59,18 @@ fn bench_normal_vec(c: &mut BenchmarkGroup<WallTime>) {
 }
 
 fn bench_poly_vec(c: &mut BenchmarkGroup<WallTime>) {
-    let mut poly_vec_ref = PolyPtrVec::<[usize]>::with_capacity(1024);
-    poly_vec_ref.push([1; 1]);
-    poly_vec_ref.push([2; 2]);
-    poly_vec_ref.push([3; 3]);
-    poly_vec_ref.push([4; 4]);
-    poly_vec_ref.push([5; 5]);
-    poly_vec_ref.push([6; 6]);
-    poly_vec_ref.push([7; 7]);
-    poly_vec_ref.push([8; 8]);
-    poly_vec_ref.push([9; 9]);
-    poly_vec_ref.push([10; 10]);
-    let result = || poly_vec_ref.iter().flat_map(|slice| slice).sum::<usize>();
+    let mut poly_vec_ref = PolyPtrVec::<[usize]>::with_capacity::<[usize; 10]>(1024);
+    poly_push!(poly_vec_ref, [1; 1]);
+    poly_push!(poly_vec_ref, [2; 2]);
+    poly_push!(poly_vec_ref, [3; 3]);
+    poly_push!(poly_vec_ref, [4; 4]);
+    poly_push!(poly_vec_ref, [5; 5]);
+    poly_push!(poly_vec_ref, [6; 6]);
+    poly_push!(poly_vec_ref, [7; 7]);
+    poly_push!(poly_vec_ref, [8; 8]);
+    poly_push!(poly_vec_ref, [9; 9]);
+    poly_push!(poly_vec_ref, [10; 10]);
+    let result = || poly_vec_ref.iter().flatten().sum::<usize>();
 
     c.bench_function("Vec<dyn>: ptr", |b| {
         b.iter(|| {
@@ -0,0 +1,169 @@
+//! Allocator support for [`PolyPtrVec`](crate::trait_vec::PolyPtrVec).
+//!
+//! A minimal copy of the unstable `Allocator` trait of the standard library is provided,
+//! so custom allocators can be plugged in on stable Rust as well. With the `allocator_api`
+//! feature every `core::alloc::Allocator` implements it too, so allocators written
+//! against either trait work side by side.
+//!
+//! To share one allocator between several vecs, implement the trait for a reference
+//! or another cheap handle to it.
+
+use alloc::alloc::{self as heap, Layout};
+use core::fmt;
+use core::ptr::{self, NonNull};
+
+/// The allocator failed to provide memory for a layout.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AllocError;
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("memory allocation failed")
+    }
+}
+
+/// Stable subset of the unstable `core::alloc::Allocator` trait.
+///
+/// # Safety
+/// Same contract as `core::alloc::Allocator`: returned blocks have to stay valid until
+/// they are deallocated, and moving or cloning the allocator must keep them valid.
+pub unsafe trait Allocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError>;
+
+    /// # Safety
+    /// `ptr` has to be a block currently allocated by this allocator with `layout`.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+
+    /// # Safety
+    /// `ptr` has to be a block currently allocated by this allocator with `old_layout`,
+    /// and `new_layout` must not be smaller than `old_layout`.
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+        let new_ptr = self.allocate(new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast().as_ptr(), old_layout.size());
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+
+    /// # Safety
+    /// `ptr` has to be a block currently allocated by this allocator with `old_layout`,
+    /// and `new_layout` must not be larger than `old_layout`.
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() <= old_layout.size());
+        let new_ptr = self.allocate(new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast().as_ptr(), new_layout.size());
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl<A: core::alloc::Allocator + ?Sized> Allocator for A {
+    #[inline]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        core::alloc::Allocator::allocate(self, layout).map_err(|_| AllocError)
+    }
+
+    #[inline]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        core::alloc::Allocator::deallocate(self, ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        core::alloc::Allocator::grow(self, ptr, old_layout, new_layout).map_err(|_| AllocError)
+    }
+
+    #[inline]
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        core::alloc::Allocator::shrink(self, ptr, old_layout, new_layout).map_err(|_| AllocError)
+    }
+}
+
+/// The global memory allocator, the default allocator of every container.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Global;
+
+unsafe impl Allocator for Global {
+    #[inline]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = if layout.size() == 0 {
+            dangling(layout)
+        } else {
+            NonNull::new(unsafe { heap::alloc(layout) }).ok_or(AllocError)?
+        };
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    #[inline]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            heap::dealloc(ptr.as_ptr(), layout)
+        }
+    }
+
+    #[inline]
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if old_layout.size() == 0 || old_layout.align() != new_layout.align() {
+            let new_ptr = self.allocate(new_layout)?;
+            ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast().as_ptr(), old_layout.size());
+            self.deallocate(ptr, old_layout);
+            return Ok(new_ptr);
+        }
+        let new_ptr = heap::realloc(ptr.as_ptr(), old_layout, new_layout.size());
+        let new_ptr = NonNull::new(new_ptr).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(new_ptr, new_layout.size()))
+    }
+
+    #[inline]
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if new_layout.size() == 0 {
+            self.deallocate(ptr, old_layout);
+            return Ok(NonNull::slice_from_raw_parts(dangling(new_layout), 0));
+        }
+        if old_layout.align() != new_layout.align() {
+            let new_ptr = self.allocate(new_layout)?;
+            ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast().as_ptr(), new_layout.size());
+            self.deallocate(ptr, old_layout);
+            return Ok(new_ptr);
+        }
+        let new_ptr = heap::realloc(ptr.as_ptr(), old_layout, new_layout.size());
+        let new_ptr = NonNull::new(new_ptr).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(new_ptr, new_layout.size()))
+    }
+}
+
+#[inline(always)]
+fn dangling(layout: Layout) -> NonNull<u8> {
+    unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(layout.align())) }
+}
@@ -0,0 +1,279 @@
+//! Append-only arena of unsized values.
+
+use crate::segmented::{SegmentView, SegmentedIterMut, Segments, DEFAULT_CHUNK_CAPACITY};
+use crate::trait_vec::coerce_value;
+use core::cell::UnsafeCell;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+#[cfg(feature = "nightly")]
+use core::marker::Unsize;
+use core::slice::Iter;
+
+/// Arena of unsized values, allocated through a shared reference.
+///
+/// Values are laid out next to each other in chunks like the elements of a
+/// [`SegmentedPolyVec`](crate::segmented::SegmentedPolyVec) and never move,
+/// so every [`PolyArena::alloc_coerce`] hands out a reference living as long as the arena.
+/// All values are dropped with the arena, in allocation order.
+pub struct PolyArena<T: ?Sized> {
+    segments: UnsafeCell<Segments<T>>,
+}
+
+// Allocating through `&self` isn't synchronized, so the arena is only `Send`.
+unsafe impl<T: ?Sized + Send> Send for PolyArena<T> {}
+
+impl<T: ?Sized> Default for PolyArena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized> PolyArena<T> {
+    /// Evaluates the receiver of the `poly_*!` macros outside of their `unsafe` block.
+    #[doc(hidden)]
+    #[inline(always)]
+    pub fn __receiver(&self) -> &Self {
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self::with_chunk_capacity(DEFAULT_CHUNK_CAPACITY)
+    }
+
+    /// Creates an empty arena whose first chunk holds `bytes` bytes.
+    /// Nothing is allocated before the first value.
+    #[inline]
+    #[must_use]
+    pub const fn with_chunk_capacity(bytes: usize) -> Self {
+        Self {
+            segments: UnsafeCell::new(Segments::new(bytes)),
+        }
+    }
+
+    /// Number of values allocated so far.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        unsafe { (*self.segments.get()).len() }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[cfg(feature = "nightly")]
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc<U: Unsize<T>>(&self, value: U) -> &mut T {
+        // The closure returns its argument
+        unsafe { self.alloc_coerce(value, |value| value) }
+    }
+
+    /// Moves `value` into the arena and returns a reference to it, valid until the arena is dropped.
+    ///
+    /// # Safety
+    /// `coerce` has to return its argument, only coerced to `T`.
+    #[inline]
+    #[track_caller]
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn alloc_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &self,
+        mut value: U,
+        coerce: F,
+    ) -> &mut T {
+        let pointee = coerce_value(&mut value, coerce);
+        // Every value gets its own bytes, so the returned reference is unique
+        let segments = unsafe { &mut *self.segments.get() };
+        unsafe { &mut *segments.push_value(value, pointee) }
+    }
+
+    /// Iterates over the values in allocation order.
+    ///
+    /// Takes `&mut self` because the references returned by [`PolyArena::alloc_coerce`]
+    /// are unique, they have to be gone before the values can be visited again.
+    ///
+    /// ```compile_fail,E0502
+    /// # use std::fmt::Debug;
+    /// # use trait_vec::arena::PolyArena;
+    /// # use trait_vec::poly_alloc;
+    /// let mut arena = PolyArena::<dyn Debug>::new();
+    /// let one = poly_alloc!(arena, 1u8);
+    /// assert_eq!(arena.iter().count(), 1);
+    /// println!("{one:?}");
+    /// ```
+    #[inline]
+    pub fn iter(&mut self) -> ArenaIter<'_, T> {
+        ArenaIter {
+            iter: self.segments.get_mut().views().iter(),
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn iter_mut(&mut self) -> SegmentedIterMut<'_, T> {
+        SegmentedIterMut::new(self.segments.get_mut().views())
+    }
+}
+
+/// Iterator over the values of a [`PolyArena`], in allocation order.
+pub struct ArenaIter<'a, T: ?Sized> {
+    iter: Iter<'a, SegmentView<T>>,
+    _marker: PhantomData<&'a T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Send for ArenaIter<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for ArenaIter<'_, T> {}
+
+impl<'a, T: ?Sized> Iterator for ArenaIter<'a, T> {
+    type Item = &'a T;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|view| unsafe { &*view.as_ptr() })
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<T: ?Sized> DoubleEndedIterator for ArenaIter<'_, T> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|view| unsafe { &*view.as_ptr() })
+    }
+}
+
+impl<T: ?Sized> ExactSizeIterator for ArenaIter<'_, T> {}
+
+impl<T: ?Sized> FusedIterator for ArenaIter<'_, T> {}
+
+impl<T: ?Sized> Clone for ArenaIter<'_, T> {
+    fn clone(&self) -> Self {
+        Self {
+            iter: self.iter.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Allocates a value in a [`PolyArena`], coercing it to the element type.
+///
+/// Works on stable Rust, where `PolyArena::alloc` isn't available.
+///
+/// ```
+/// use std::fmt::Debug;
+/// use trait_vec::arena::PolyArena;
+/// use trait_vec::poly_alloc;
+///
+/// let arena = PolyArena::<dyn Debug>::new();
+/// let one = poly_alloc!(arena, 1u8);
+/// let two = poly_alloc!(arena, String::from("two"));
+/// assert_eq!(format!("{one:?} {two:?}"), "1 \"two\"");
+/// ```
+#[macro_export]
+macro_rules! poly_alloc {
+    ($arena:expr, $value:expr $(,)?) => {
+        match ($arena.__receiver(), $value) {
+            // The closure returns its argument
+            (arena, value) => unsafe { arena.alloc_coerce(value, |value| value) },
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::assert_drops_once;
+    use std::fmt::Debug;
+
+    trait Node: Debug {
+        fn children(&self) -> &[&dyn Node];
+
+        fn depth(&self) -> usize {
+            1 + self
+                .children()
+                .iter()
+                .map(|child| child.depth())
+                .max()
+                .unwrap_or(0)
+        }
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    struct Leaf(i64);
+
+    impl Node for Leaf {
+        fn children(&self) -> &[&dyn Node] {
+            &[]
+        }
+    }
+
+    #[derive(Debug)]
+    struct Add<'a>([&'a dyn Node; 2]);
+
+    impl Node for Add<'_> {
+        fn children(&self) -> &[&dyn Node] {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn alloc_links_values() {
+        let leaves = PolyArena::<dyn Node>::with_chunk_capacity(16);
+        let one: &dyn Node = poly_alloc!(leaves, Leaf(1));
+        let two: &dyn Node = poly_alloc!(leaves, Leaf(2));
+        let three: &dyn Node = poly_alloc!(leaves, Leaf(3));
+        // References into the first arena outlive the second one
+        let sums = PolyArena::<dyn Node + '_>::new();
+        let sum = poly_alloc!(sums, Add([one, two]));
+        assert_eq!(sum.depth(), 2);
+        let sum = poly_alloc!(sums, Add([three, one]));
+        assert_eq!(format!("{sum:?}"), "Add([Leaf(3), Leaf(1)])");
+        assert_eq!(leaves.len(), 3);
+        assert_eq!(sums.len(), 2);
+    }
+
+    #[test]
+    fn iter_in_allocation_order() {
+        let mut arena = PolyArena::<dyn Debug>::with_chunk_capacity(8);
+        for value in 0..20u32 {
+            poly_alloc!(arena, value);
+        }
+        poly_alloc!(arena, "last");
+        let names: Vec<String> = arena.iter().map(|value| format!("{value:?}")).collect();
+        assert_eq!(names.len(), 21);
+        assert_eq!(names[..3], ["0", "1", "2"]);
+        assert_eq!(names[20], "\"last\"");
+        assert_eq!(
+            format!("{:?}", arena.iter().next_back().unwrap()),
+            "\"last\""
+        );
+
+        let mut arena = PolyArena::<[u8]>::new();
+        poly_alloc!(arena, [1u8; 3]);
+        let two = poly_alloc!(arena, [2u8; 2]);
+        two[1] = 7;
+        for value in arena.iter_mut() {
+            value[0] = 0;
+        }
+        assert!(arena.iter().eq([&[0u8, 1, 1][..], &[0u8, 7]]));
+    }
+
+    #[test]
+    fn drop_runs_destructors() {
+        assert_drops_once(|counter| {
+            let arena = PolyArena::<dyn Debug>::with_chunk_capacity(8);
+            for _ in 0..5 {
+                poly_alloc!(arena, counter());
+            }
+            arena
+        });
+    }
+}
@@ -1,49 +1,198 @@
-use std::marker::Unsize;
-use std::ops::{CoerceUnsized, Deref, DerefMut, DispatchFromDyn};
-use std::ptr::{NonNull, Pointee};
-use std::{fmt, ptr};
+use core::any::TypeId;
+use core::marker::PhantomData;
+#[cfg(feature = "nightly")]
+use core::marker::Unsize;
+#[cfg(feature = "nightly")]
+use core::ops::{CoerceUnsized, DispatchFromDyn};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::{fmt, mem, ptr};
+
+/// Metadata half of a `*const T`, used to rebuild pointers to values at other addresses.
+#[cfg(feature = "nightly")]
+struct PtrMetadata<T: ?Sized>(<T as ptr::Pointee>::Metadata);
+
+#[cfg(feature = "nightly")]
+impl<T: ?Sized> PtrMetadata<T> {
+    #[inline(always)]
+    fn of(ptr: *const T) -> Self {
+        Self(ptr.to_raw_parts().1)
+    }
+
+    #[inline(always)]
+    fn ptr_at(&self, data_ptr: *mut ()) -> *mut T {
+        ptr::from_raw_parts_mut::<T>(data_ptr, self.0)
+    }
+}
+
+/// Metadata half of a `*const T`, used to rebuild pointers to values at other addresses.
+///
+/// Stable Rust can't name pointer metadata, so the whole pointer is kept and only its
+/// data address gets replaced. Pointers to unsized values are laid out as the data
+/// address followed by the metadata, the same assumption crates like `smallbox` make.
+#[cfg(not(feature = "nightly"))]
+struct PtrMetadata<T: ?Sized>(*const T);
+
+#[cfg(not(feature = "nightly"))]
+impl<T: ?Sized> PtrMetadata<T> {
+    #[inline(always)]
+    fn of(ptr: *const T) -> Self {
+        const {
+            assert!(
+                size_of::<*const T>() == size_of::<*const ()>()
+                    || size_of::<*const T>() == 2 * size_of::<*const ()>()
+            )
+        };
+        Self(ptr)
+    }
+
+    #[inline(always)]
+    fn ptr_at(&self, data_ptr: *mut ()) -> *mut T {
+        let mut ptr = self.0 as *mut T;
+        unsafe { ptr::write(&mut ptr as *mut *mut T as *mut *mut (), data_ptr) };
+        debug_assert_eq!(ptr as *mut (), data_ptr);
+        ptr
+    }
+}
+
+impl<T: ?Sized> Clone for PtrMetadata<T> {
+    #[inline(always)]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for PtrMetadata<T> {}
+
+// Only the metadata is ever read, which is plain data like a vtable pointer or a length.
+unsafe impl<T: ?Sized> Send for PtrMetadata<T> {}
+unsafe impl<T: ?Sized> Sync for PtrMetadata<T> {}
+
+/// [`TypeId`] of `U` with every lifetime in it replaced by `'static`.
+///
+/// Lifetimes are erased before code generation, so the vtable below is the same for all of them.
+fn erased_type_id<U: ?Sized>() -> TypeId {
+    trait Erased {
+        fn id(&self) -> TypeId
+        where
+            Self: 'static;
+    }
+
+    impl<U: ?Sized> Erased for PhantomData<U> {
+        fn id(&self) -> TypeId
+        where
+            Self: 'static,
+        {
+            TypeId::of::<U>()
+        }
+    }
+
+    let marker: &dyn Erased = &PhantomData::<U>;
+    let marker: &(dyn Erased + 'static) = unsafe { mem::transmute(marker) };
+    marker.id()
+}
+
+/// `dead_size` of views whose element is alive.
+const LIVE: usize = usize::MAX;
 
 pub struct OffsetDynView<T: ?Sized> {
     pub(crate) offset: isize,
-    ptr_metadata: <T as Pointee>::Metadata,
+    /// Size of the dropped element if this view is a tombstone, [`LIVE`] otherwise
+    dead_size: usize,
+    /// Type of the element with its lifetimes erased, if it was recorded
+    value_type: Option<TypeId>,
+    ptr_metadata: PtrMetadata<T>,
 }
 
 impl<T: ?Sized> Clone for OffsetDynView<T> {
     fn clone(&self) -> Self {
         Self {
             offset: self.offset,
+            dead_size: self.dead_size,
+            value_type: self.value_type,
             ptr_metadata: self.ptr_metadata,
         }
     }
 }
 
-impl<'a, T: ?Sized + 'a> OffsetDynView<T> {
+impl<T: ?Sized> OffsetDynView<T> {
     unsafe fn offset_ptr(&self, valid_ptr: *const ()) -> *const () {
-        (valid_ptr as *const u8).offset(-self.offset) as *const ()
+        (valid_ptr as *const u8).offset(self.offset) as *const ()
     }
 
     unsafe fn offset_ptr_mut(&self, valid_ptr: *mut ()) -> *mut () {
-        (valid_ptr as *mut u8).offset(-self.offset) as *mut ()
+        (valid_ptr as *mut u8).offset(self.offset) as *mut ()
     }
 
     pub fn from_ptr(offset: isize, invalid_ptr: *const T) -> Self {
         Self {
             offset,
-            ptr_metadata: invalid_ptr.to_raw_parts().1,
+            dead_size: LIVE,
+            value_type: None,
+            ptr_metadata: PtrMetadata::of(invalid_ptr),
         }
     }
 
+    /// Like [`OffsetDynView::from_ptr`], and records that the element is a `U`.
+    #[inline]
+    pub fn from_value_ptr<U>(offset: isize, invalid_ptr: *const T) -> Self {
+        Self {
+            value_type: Some(erased_type_id::<U>()),
+            ..Self::from_ptr(offset, invalid_ptr)
+        }
+    }
+
+    /// Whether the element was recorded as a `U` by [`OffsetDynView::from_value_ptr`]
+    /// and isn't dead.
+    ///
+    /// Lifetimes are erased from the recorded type, so this only tells `'static` types apart
+    /// if every element is `'static`, as is the case when `T` is.
+    #[inline(always)]
+    pub fn holds<U: 'static>(&self) -> bool {
+        !self.is_dead() && self.value_type == Some(TypeId::of::<U>())
+    }
+
+    /// Whether the element of this view was dropped, leaving a tombstone behind.
+    #[inline(always)]
+    pub fn is_dead(&self) -> bool {
+        self.dead_size != LIVE
+    }
+
+    /// Number of bytes still occupied by the dropped element of a tombstone.
+    #[inline(always)]
+    pub(crate) fn dead_size(&self) -> Option<usize> {
+        self.is_dead().then_some(self.dead_size)
+    }
+
+    /// Marks the element as dropped, `size` of its bytes stay in place until compaction.
+    #[inline(always)]
+    pub(crate) fn kill(&mut self, size: usize) {
+        debug_assert!(size != LIVE);
+        self.dead_size = size;
+    }
+
+    /// Pointer to a value placed at `data_ptr`, carrying the metadata stored in this view.
+    #[inline]
+    pub fn ptr_at(&self, data_ptr: *mut ()) -> *mut T {
+        self.ptr_metadata.ptr_at(data_ptr)
+    }
+
+    /// # Safety
+    /// `valid_ptr` must be the base of the buffer this view was created for,
+    /// and the element at `offset` must still be alive.
     #[inline]
     pub unsafe fn as_view(&self, valid_ptr: *const ()) -> DynViewPtr<T> {
         let data_ptr = self.offset_ptr(valid_ptr);
-        let t_ptr = ptr::from_raw_parts::<T>(data_ptr, self.ptr_metadata) as *const T;
+        let t_ptr = self.ptr_metadata.ptr_at(data_ptr as *mut ());
         DynViewPtr::<T>::from_ptr_unchecked(t_ptr)
     }
 
+    /// # Safety
+    /// Same as [`OffsetDynView::as_view`].
     #[inline]
     pub unsafe fn as_mut_view(&self, valid_ptr: *mut ()) -> DynViewPtr<T> {
         let data_ptr = self.offset_ptr_mut(valid_ptr);
-        let t_ptr = ptr::from_raw_parts_mut::<T>(data_ptr, self.ptr_metadata);
+        let t_ptr = self.ptr_metadata.ptr_at(data_ptr);
         DynViewPtr::<T>::from_mut_ptr_unchecked(t_ptr)
     }
 }
@@ -56,16 +205,13 @@ where
     pointer: NonNull<T>,
 }
 
-impl<'a, T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<DynViewPtr<U>> for DynViewPtr<T> {}
-
-impl<'a, T: ?Sized + Unsize<U>, U: ?Sized> DispatchFromDyn<DynViewPtr<U>> for DynViewPtr<T> {}
+#[cfg(feature = "nightly")]
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<DynViewPtr<U>> for DynViewPtr<T> {}
 
-impl<'a, T: ?Sized> DynViewPtr<T> {
-    #[inline(always)]
-    fn as_ref<'b>(&'b self) -> &'a T {
-        unsafe { self.pointer.as_ref() }
-    }
+#[cfg(feature = "nightly")]
+impl<T: ?Sized + Unsize<U>, U: ?Sized> DispatchFromDyn<DynViewPtr<U>> for DynViewPtr<T> {}
 
+impl<T: ?Sized> DynViewPtr<T> {
     #[inline(always)]
     pub fn inner(&self) -> &NonNull<T> {
         &self.pointer
@@ -88,11 +234,15 @@ impl<'a, T: ?Sized + 'a> DynViewPtr<T> {
         }
     }*/
 
+    /// # Safety
+    /// `ptr` must be non-null.
     #[inline]
     pub unsafe fn from_mut_ptr_unchecked(ptr: *mut T) -> DynViewPtr<T> {
         unsafe { DynViewPtr::<T>::from_inner(NonNull::new_unchecked(ptr)) }
     }
 
+    /// # Safety
+    /// `ptr` must be non-null.
     #[inline]
     pub unsafe fn from_ptr_unchecked(ptr: *const T) -> DynViewPtr<T> {
         unsafe { DynViewPtr::<T>::from_inner(NonNull::new_unchecked(ptr as *mut T)) }
@@ -106,6 +256,11 @@ impl<'a, T: ?Sized + 'a> DynViewPtr<T> {
     pub fn into_inner(self) -> &'a T {
         unsafe { self.pointer.as_ref() }
     }
+
+    #[inline]
+    pub fn into_inner_mut(mut self) -> &'a mut T {
+        unsafe { self.pointer.as_mut() }
+    }
 }
 
 impl<T: fmt::Display + ?Sized> fmt::Display for DynViewPtr<T> {
@@ -127,7 +282,7 @@ impl<T: ?Sized> fmt::Pointer for DynViewPtr<T> {
     }
 }
 
-impl<'a, T: ?Sized + 'a> Deref for DynViewPtr<T> {
+impl<T: ?Sized> Deref for DynViewPtr<T> {
     type Target = T;
 
     #[inline]
@@ -136,7 +291,7 @@ impl<'a, T: ?Sized + 'a> Deref for DynViewPtr<T> {
     }
 }
 
-impl<'a, T: ?Sized + 'a> DerefMut for DynViewPtr<T> {
+impl<T: ?Sized> DerefMut for DynViewPtr<T> {
     #[inline]
     fn deref_mut(&mut self) -> &mut T {
         unsafe { self.pointer.as_mut() }
@@ -148,6 +303,7 @@ mod test {
     use crate::dyn_view_ptr::DynViewPtr;
     use std::fmt::Debug;
 
+    #[allow(dead_code)]
     #[derive(Debug)]
     struct Example {
         inner: f64,
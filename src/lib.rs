@@ -1,21 +1,25 @@
-
 // Unstable features
-#![feature(unsize)] //Coercion
-#![feature(ptr_metadata)]
-#![feature(coerce_unsized)]
-#![feature(dispatch_from_dyn)]
-
-//For tests
-#![feature(strict_provenance)]
-
-extern crate core;
-
-use std::slice::{Iter, SliceIndex};
-use crate::dyn_view_ptr::DynViewPtr;
-use crate::trait_vec::PolyPtrVec;
-
-pub mod trait_vec;
+#![cfg_attr(feature = "nightly", feature(unsize))] //Coercion
+#![cfg_attr(feature = "nightly", feature(ptr_metadata))]
+#![cfg_attr(feature = "nightly", feature(coerce_unsized))]
+#![cfg_attr(feature = "nightly", feature(dispatch_from_dyn))]
+#![cfg_attr(feature = "nightly", feature(dropck_eyepatch))] //Drop of borrowed elements
+#![cfg_attr(feature = "nightly", feature(try_reserve_kind))]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod allocator;
+pub mod arena;
 pub mod dyn_view_ptr;
+mod raw_buf;
+pub mod segmented;
+pub mod slot_map;
+pub mod storage;
+#[cfg(test)]
+mod test_support;
+pub mod trait_vec;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -0,0 +1,496 @@
+use crate::allocator::Allocator;
+use crate::dyn_view_ptr::OffsetDynView;
+use alloc::alloc::{self as heap, Layout};
+use alloc::collections::TryReserveError;
+#[cfg(feature = "nightly")]
+use alloc::collections::TryReserveErrorKind;
+use core::mem::{align_of, size_of};
+use core::ops::Range;
+use core::ptr::{self, NonNull};
+use core::slice;
+
+/// Why growing a [`RawPolyBuf`] failed.
+///
+/// Public only to appear in the sealed storage trait, the module itself is private.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReserveError {
+    CapacityOverflow,
+    AllocError(Layout),
+}
+
+impl ReserveError {
+    #[cold]
+    #[track_caller]
+    pub(crate) fn handle(self) -> ! {
+        match self {
+            ReserveError::CapacityOverflow => panic!("capacity overflow"),
+            ReserveError::AllocError(layout) => heap::handle_alloc_error(layout),
+        }
+    }
+}
+
+impl From<ReserveError> for TryReserveError {
+    #[cfg(feature = "nightly")]
+    fn from(err: ReserveError) -> Self {
+        match err {
+            ReserveError::CapacityOverflow => TryReserveErrorKind::CapacityOverflow.into(),
+            ReserveError::AllocError(layout) => TryReserveErrorKind::AllocError {
+                layout,
+                non_exhaustive: (),
+            }
+            .into(),
+        }
+    }
+
+    /// `TryReserveError` can't be built on stable, so every failure is reported the way
+    /// `Vec` reports a capacity overflow.
+    #[cfg(not(feature = "nightly"))]
+    fn from(_: ReserveError) -> Self {
+        alloc::vec::Vec::<u8>::new()
+            .try_reserve(usize::MAX)
+            .expect_err("reserving usize::MAX bytes overflows the capacity")
+    }
+}
+
+/// Growable byte storage whose base address is always aligned to the largest
+/// alignment requested so far, so offsets padded relative to the base stay
+/// aligned in absolute terms too.
+///
+/// The allocator is kept by the container, every method taking one has to be given
+/// the allocator the buffer was grown with. The memory is only released by [`RawPolyBuf::free`].
+pub(crate) struct RawPolyBuf {
+    ptr: NonNull<u8>,
+    cap: usize,
+    len: usize,
+    align: usize,
+}
+
+// The buffer only holds plain bytes, ownership of the values written into it
+// is tracked by the containers.
+unsafe impl Send for RawPolyBuf {}
+unsafe impl Sync for RawPolyBuf {}
+
+impl RawPolyBuf {
+    #[inline]
+    pub(crate) const fn new() -> Self {
+        Self {
+            ptr: dangling(1),
+            cap: 0,
+            len: 0,
+            align: 1,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn with_capacity<A: Allocator>(capacity: usize, align: usize, alloc: &A) -> Self {
+        let mut buf = Self::new();
+        buf.reserve_exact(capacity, align, alloc);
+        buf
+    }
+
+    #[inline(always)]
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    #[inline(always)]
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// # Safety
+    /// `len` must not exceed the capacity and the bytes up to it must be initialized.
+    #[inline(always)]
+    pub(crate) unsafe fn set_len(&mut self, len: usize) {
+        debug_assert!(len <= self.cap);
+        self.len = len;
+    }
+
+    #[inline(always)]
+    pub(crate) fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    #[inline(always)]
+    pub(crate) fn align(&self) -> usize {
+        self.align
+    }
+
+    #[inline]
+    pub(crate) fn reserve_exact<A: Allocator>(
+        &mut self,
+        additional: usize,
+        align: usize,
+        alloc: &A,
+    ) {
+        if let Err(err) = self.try_reserve_exact(additional, align, alloc) {
+            err.handle()
+        }
+    }
+
+    pub(crate) fn try_reserve<A: Allocator>(
+        &mut self,
+        additional: usize,
+        align: usize,
+        alloc: &A,
+    ) -> Result<(), ReserveError> {
+        if self.fits(additional, align) {
+            return Ok(());
+        }
+        let required = self
+            .len
+            .checked_add(additional)
+            .ok_or(ReserveError::CapacityOverflow)?;
+        let new_cap = if required <= self.cap {
+            self.cap
+        } else {
+            required.max(self.cap * 2).max(8)
+        };
+        self.grow_to(new_cap, align.max(self.align), alloc)
+    }
+
+    pub(crate) fn try_reserve_exact<A: Allocator>(
+        &mut self,
+        additional: usize,
+        align: usize,
+        alloc: &A,
+    ) -> Result<(), ReserveError> {
+        if self.fits(additional, align) {
+            return Ok(());
+        }
+        let required = self
+            .len
+            .checked_add(additional)
+            .ok_or(ReserveError::CapacityOverflow)?;
+        self.grow_to(required.max(self.cap), align.max(self.align), alloc)
+    }
+
+    pub(crate) fn shrink_to<A: Allocator>(&mut self, min_capacity: usize, alloc: &A) {
+        let new_cap = min_capacity.max(self.len);
+        if new_cap >= self.cap {
+            return;
+        }
+        unsafe {
+            if new_cap == 0 {
+                self.free(alloc);
+                return;
+            }
+            let new_layout = Layout::from_size_align_unchecked(new_cap, self.align);
+            match alloc.shrink(self.ptr, self.layout(), new_layout) {
+                Ok(ptr) => self.ptr = ptr.cast(),
+                Err(_) => heap::handle_alloc_error(new_layout),
+            }
+        }
+        self.cap = new_cap;
+    }
+
+    /// Releases the allocation, the buffer is left empty.
+    pub(crate) fn free<A: Allocator>(&mut self, alloc: &A) {
+        if self.cap != 0 {
+            unsafe { alloc.deallocate(self.ptr, self.layout()) }
+        }
+        self.ptr = dangling(self.align);
+        self.cap = 0;
+        self.len = 0;
+    }
+
+    #[inline(always)]
+    fn layout(&self) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(self.cap, self.align) }
+    }
+
+    #[inline(always)]
+    fn fits(&self, additional: usize, align: usize) -> bool {
+        align <= self.align && self.cap - self.len >= additional
+    }
+
+    /// Moves the contents into an allocation of `new_cap` bytes aligned to `new_align`.
+    /// Offsets are kept as they are, which is enough because every padded offset
+    /// was computed for an alignment that divides `new_align`.
+    fn grow_to<A: Allocator>(
+        &mut self,
+        new_cap: usize,
+        new_align: usize,
+        alloc: &A,
+    ) -> Result<(), ReserveError> {
+        let new_layout = Layout::from_size_align(new_cap, new_align)
+            .map_err(|_| ReserveError::CapacityOverflow)?;
+        if new_cap == 0 {
+            self.ptr = dangling(new_align);
+            self.align = new_align;
+            return Ok(());
+        }
+
+        let ptr = if self.cap == 0 {
+            alloc.allocate(new_layout)
+        } else {
+            unsafe { alloc.grow(self.ptr, self.layout(), new_layout) }
+        };
+        self.ptr = ptr
+            .map_err(|_| ReserveError::AllocError(new_layout))?
+            .cast();
+        self.cap = new_cap;
+        self.align = new_align;
+        Ok(())
+    }
+}
+
+impl Default for RawPolyBuf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Growable table of plain records of a container, allocated like [`RawPolyBuf`].
+/// Records are never dropped, they are only forgotten when the table is freed.
+pub(crate) struct RawTable<E> {
+    ptr: NonNull<E>,
+    cap: usize,
+    len: usize,
+}
+
+/// Table of the views of a container.
+pub(crate) type ViewTable<T> = RawTable<OffsetDynView<T>>;
+
+// Records are plain offsets and metadata, the elements they describe are owned by the containers.
+unsafe impl<E> Send for RawTable<E> {}
+unsafe impl<E> Sync for RawTable<E> {}
+
+impl<E> RawTable<E> {
+    #[inline]
+    pub(crate) const fn new() -> Self {
+        Self {
+            ptr: NonNull::dangling(),
+            cap: 0,
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn with_capacity<A: Allocator>(capacity: usize, alloc: &A) -> Self {
+        let mut table = Self::new();
+        if let Err(err) = table.grow_to(capacity, alloc) {
+            err.handle()
+        }
+        table
+    }
+
+    #[inline(always)]
+    pub(crate) fn as_ptr(&self) -> *const E {
+        self.ptr.as_ptr()
+    }
+
+    #[inline(always)]
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut E {
+        self.ptr.as_ptr()
+    }
+
+    #[inline(always)]
+    pub(crate) fn as_slice(&self) -> &[E] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+
+    #[inline(always)]
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [E] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+    }
+
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// # Safety
+    /// `len` must not exceed the capacity and the records up to it must be initialized.
+    #[inline(always)]
+    pub(crate) unsafe fn set_len(&mut self, len: usize) {
+        debug_assert!(len <= self.cap);
+        self.len = len;
+    }
+
+    #[inline]
+    pub(crate) fn try_reserve<A: Allocator>(
+        &mut self,
+        additional: usize,
+        alloc: &A,
+    ) -> Result<(), ReserveError> {
+        if self.cap - self.len >= additional {
+            return Ok(());
+        }
+        let required = self
+            .len
+            .checked_add(additional)
+            .ok_or(ReserveError::CapacityOverflow)?;
+        self.grow_to(required.max(self.cap * 2).max(4), alloc)
+    }
+
+    #[inline]
+    pub(crate) fn push<A: Allocator>(&mut self, record: E, alloc: &A) -> Result<(), ReserveError> {
+        self.try_reserve(1, alloc)?;
+        unsafe { self.as_mut_ptr().add(self.len).write(record) };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes the record at `index` and moves the last one into its place.
+    #[inline]
+    pub(crate) fn swap_remove(&mut self, index: usize) -> E {
+        let last = self.len - 1;
+        self.as_mut_slice().swap(index, last);
+        self.len = last;
+        unsafe { self.as_ptr().add(last).read() }
+    }
+
+    /// Releases the allocation, the table is left empty.
+    pub(crate) fn free<A: Allocator>(&mut self, alloc: &A) {
+        if self.cap != 0 {
+            // The layout was valid when it was allocated
+            let layout = unsafe {
+                Layout::from_size_align_unchecked(self.cap * size_of::<E>(), align_of::<E>())
+            };
+            unsafe { alloc.deallocate(self.ptr.cast(), layout) }
+        }
+        *self = Self::new();
+    }
+
+    fn grow_to<A: Allocator>(&mut self, new_cap: usize, alloc: &A) -> Result<(), ReserveError> {
+        if new_cap <= self.cap {
+            return Ok(());
+        }
+        let new_layout = Layout::array::<E>(new_cap).map_err(|_| ReserveError::CapacityOverflow)?;
+        let ptr = if self.cap == 0 {
+            alloc.allocate(new_layout)
+        } else {
+            unsafe {
+                let old_layout = Layout::from_size_align_unchecked(
+                    self.cap * size_of::<E>(),
+                    new_layout.align(),
+                );
+                alloc.grow(self.ptr.cast(), old_layout, new_layout)
+            }
+        };
+        self.ptr = ptr
+            .map_err(|_| ReserveError::AllocError(new_layout))?
+            .cast();
+        self.cap = new_cap;
+        Ok(())
+    }
+}
+
+/// Holes left in a [`RawPolyBuf`] by removals in sparse mode, see
+/// [`PolyVec::set_sparse`](crate::trait_vec::PolyVec::set_sparse).
+///
+/// Allocated like the buffer, so it is kept by the storage owning the buffer and
+/// has to be given the same allocator. The memory is only released by [`FreeList::free`].
+pub struct FreeList {
+    /// Holes by size class, class `k` holds the holes of `2^(k-1)..2^k` bytes
+    classes: RawTable<RawTable<Range<usize>>>,
+    /// Whether offsets may be out of view order or have holes between them
+    pub(crate) fragmented: bool,
+}
+
+impl FreeList {
+    #[inline]
+    pub(crate) const fn new() -> Self {
+        Self {
+            classes: RawTable::new(),
+            fragmented: false,
+        }
+    }
+
+    #[inline]
+    fn size_class(size: usize) -> usize {
+        (usize::BITS - size.leading_zeros()) as usize
+    }
+
+    /// Records `hole`, empty holes are ignored.
+    pub(crate) fn insert<A: Allocator>(&mut self, hole: Range<usize>, alloc: &A) {
+        if hole.is_empty() {
+            return;
+        }
+        let class = Self::size_class(hole.len());
+        let mut inserted = Ok(());
+        while inserted.is_ok() && self.classes.len() <= class {
+            inserted = self.classes.push(RawTable::new(), alloc);
+        }
+        if let Err(err) =
+            inserted.and_then(|()| self.classes.as_mut_slice()[class].push(hole, alloc))
+        {
+            err.handle()
+        }
+    }
+
+    /// Takes the first hole with room for `layout` from the smallest size class that may
+    /// have one, and returns the offset of the value. What is left of the hole stays free.
+    pub(crate) fn take<A: Allocator>(&mut self, layout: Layout, alloc: &A) -> Option<usize> {
+        if layout.size() == 0 {
+            return None;
+        }
+        for class in Self::size_class(layout.size())..self.classes.len() {
+            let fits = |hole: &Range<usize>| {
+                align_up(hole.start, layout.align()) + layout.size() <= hole.end
+            };
+            let holes = &mut self.classes.as_mut_slice()[class];
+            if let Some(index) = holes.as_slice().iter().position(fits) {
+                let hole = holes.swap_remove(index);
+                let offset = align_up(hole.start, layout.align());
+                self.insert(hole.start..offset, alloc);
+                self.insert(offset + layout.size()..hole.end, alloc);
+                return Some(offset);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.classes
+            .as_slice()
+            .iter()
+            .flat_map(RawTable::as_slice)
+            .map(|hole| hole.len())
+            .sum()
+    }
+
+    /// Forgets every hole, keeping the allocations for later ones.
+    pub(crate) fn clear(&mut self) {
+        for holes in self.classes.as_mut_slice() {
+            unsafe { holes.set_len(0) };
+        }
+        self.fragmented = false;
+    }
+
+    /// Copies the holes into a new free list allocated in `alloc`.
+    pub(crate) fn clone_in<A: Allocator>(&self, alloc: &A) -> Self {
+        let mut free = Self::new();
+        for hole in self.classes.as_slice().iter().flat_map(RawTable::as_slice) {
+            free.insert(hole.clone(), alloc);
+        }
+        free.fragmented = self.fragmented;
+        free
+    }
+
+    /// Releases the allocations, the free list is left empty.
+    pub(crate) fn free<A: Allocator>(&mut self, alloc: &A) {
+        for holes in self.classes.as_mut_slice() {
+            holes.free(alloc);
+        }
+        self.classes.free(alloc);
+        self.fragmented = false;
+    }
+}
+
+#[inline(always)]
+const fn dangling(align: usize) -> NonNull<u8> {
+    unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(align)) }
+}
+
+/// Rounds `offset` up to the next multiple of `align`, which must be a power of two.
+#[inline(always)]
+pub(crate) const fn align_up(offset: usize, align: usize) -> usize {
+    (offset + align - 1) & !(align - 1)
+}
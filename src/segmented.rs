@@ -0,0 +1,504 @@
+//! Chunked storage whose elements never move once they are pushed.
+
+use crate::allocator::Global;
+use crate::dyn_view_ptr::OffsetDynView;
+use crate::raw_buf::{align_up, RawPolyBuf};
+use crate::trait_vec::coerce_value;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+#[cfg(feature = "nightly")]
+use core::marker::Unsize;
+use core::mem::{align_of, size_of};
+use core::ops::{Index, IndexMut};
+use core::ptr::{self, NonNull};
+use core::slice::Iter;
+
+/// Size in bytes of the first chunk unless another one is requested.
+pub const DEFAULT_CHUNK_CAPACITY: usize = 256;
+
+/// View of an element together with the base of the chunk it lives in.
+pub(crate) struct SegmentView<T: ?Sized> {
+    base: NonNull<u8>,
+    view: OffsetDynView<T>,
+}
+
+impl<T: ?Sized> SegmentView<T> {
+    #[inline(always)]
+    pub(crate) fn as_ptr(&self) -> *mut T {
+        unsafe {
+            self.view
+                .as_mut_view(self.base.as_ptr() as *mut ())
+                .inner()
+                .as_ptr()
+        }
+    }
+}
+
+/// Chunks of the byte storage, only released when dropped.
+struct Chunks(Vec<RawPolyBuf>);
+
+impl Drop for Chunks {
+    fn drop(&mut self) {
+        for chunk in self.0.iter_mut() {
+            chunk.free(&Global);
+        }
+    }
+}
+
+/// Append-only element storage. Chunks are allocated with their final size and never grown,
+/// a value that doesn't fit into the last one starts a new chunk twice as large.
+pub(crate) struct Segments<T: ?Sized> {
+    chunks: Chunks,
+    views: Vec<SegmentView<T>>,
+    /// Size in bytes of the next chunk
+    next_capacity: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ?Sized> Segments<T> {
+    #[inline]
+    pub(crate) const fn new(chunk_capacity: usize) -> Self {
+        Self {
+            chunks: Chunks(Vec::new()),
+            views: Vec::new(),
+            next_capacity: chunk_capacity,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.views.len()
+    }
+
+    #[inline(always)]
+    pub(crate) fn element(&self, index: usize) -> Option<*mut T> {
+        self.views.get(index).map(SegmentView::as_ptr)
+    }
+
+    /// # Safety
+    /// `index` must be less than the length.
+    #[inline(always)]
+    pub(crate) unsafe fn element_unchecked(&self, index: usize) -> *mut T {
+        self.views.get_unchecked(index).as_ptr()
+    }
+
+    #[inline(always)]
+    pub(crate) fn views(&self) -> &[SegmentView<T>] {
+        &self.views
+    }
+
+    /// Moves `value` into the last chunk, or a new one if it doesn't fit, and returns
+    /// a pointer to it that stays valid until the segments are dropped.
+    /// `pointee` is the coerced pointer to `value`, see [`coerce_value`].
+    #[track_caller]
+    pub(crate) fn push_value<U>(&mut self, value: U, pointee: *const T) -> *mut T {
+        self.views.reserve(1);
+        let fits = self.chunks.0.last().is_some_and(|chunk| {
+            chunk.align() >= align_of::<U>()
+                && chunk.capacity() - align_up(chunk.len(), align_of::<U>()).min(chunk.capacity())
+                    >= size_of::<U>()
+        });
+        if !fits {
+            let capacity = self.next_capacity.max(size_of::<U>());
+            let align = self
+                .chunks
+                .0
+                .last()
+                .map_or(1, RawPolyBuf::align)
+                .max(align_of::<U>());
+            self.chunks.0.reserve(1);
+            self.chunks
+                .0
+                .push(RawPolyBuf::with_capacity(capacity, align, &Global));
+            self.next_capacity = capacity.saturating_mul(2);
+        }
+
+        let chunk = unsafe { self.chunks.0.last_mut().unwrap_unchecked() };
+        let offset = align_up(chunk.len(), align_of::<U>());
+        unsafe {
+            ptr::write(chunk.as_mut_ptr().add(offset) as *mut U, value);
+            chunk.set_len(offset + size_of::<U>());
+        }
+        let view = SegmentView {
+            base: unsafe { NonNull::new_unchecked(chunk.as_mut_ptr()) },
+            view: OffsetDynView::from_ptr(offset as isize, pointee),
+        };
+        let ptr = view.as_ptr();
+        self.views.push(view);
+        ptr
+    }
+}
+
+// The chunks and their views stay in place, only new ones are added.
+// Elements are dropped in order, the rest still gets dropped if one of them panics.
+#[cfg(feature = "nightly")]
+unsafe impl<#[may_dangle] T: ?Sized> Drop for Segments<T> {
+    fn drop(&mut self) {
+        drop_views(&self.views)
+    }
+}
+
+#[cfg(not(feature = "nightly"))]
+impl<T: ?Sized> Drop for Segments<T> {
+    fn drop(&mut self) {
+        drop_views(&self.views)
+    }
+}
+
+fn drop_views<T: ?Sized>(views: &[SegmentView<T>]) {
+    struct DropGuard<'a, T: ?Sized>(Iter<'a, SegmentView<T>>);
+
+    impl<T: ?Sized> Drop for DropGuard<'_, T> {
+        fn drop(&mut self) {
+            for view in self.0.by_ref() {
+                unsafe { ptr::drop_in_place(view.as_ptr()) }
+            }
+        }
+    }
+
+    let mut guard = DropGuard(views.iter());
+    for view in guard.0.by_ref() {
+        unsafe { ptr::drop_in_place(view.as_ptr()) }
+    }
+}
+
+/// Vec of unsized values that never moves its elements, so values can be pushed
+/// through a shared reference while references to earlier ones are still alive.
+///
+/// Elements are stored in chunks like in [`PolyPtrVec`](crate::trait_vec::PolyPtrVec),
+/// a full chunk is kept as it is and followed by a new one twice as large.
+pub struct SegmentedPolyVec<T: ?Sized> {
+    segments: UnsafeCell<Segments<T>>,
+}
+
+// Pushing through `&self` isn't synchronized, so the vec is only `Send`.
+unsafe impl<T: ?Sized + Send> Send for SegmentedPolyVec<T> {}
+
+impl<T: ?Sized> Default for SegmentedPolyVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized> SegmentedPolyVec<T> {
+    /// Evaluates the receiver of the `poly_*!` macros outside of their `unsafe` block.
+    #[doc(hidden)]
+    #[inline(always)]
+    pub fn __receiver(&self) -> &Self {
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self::with_chunk_capacity(DEFAULT_CHUNK_CAPACITY)
+    }
+
+    /// Creates an empty vec whose first chunk holds `bytes` bytes.
+    /// Nothing is allocated before the first push.
+    #[inline]
+    #[must_use]
+    pub const fn with_chunk_capacity(bytes: usize) -> Self {
+        Self {
+            segments: UnsafeCell::new(Segments::new(bytes)),
+        }
+    }
+
+    // Every access to the segments is short and doesn't call into user code,
+    // so no two of them overlap even though pushes only need `&self`.
+    #[inline(always)]
+    fn segments(&self) -> &Segments<T> {
+        unsafe { &*self.segments.get() }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.segments().len()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub fn push<U: Unsize<T>>(&self, value: U) -> &T {
+        // The closure returns its argument
+        unsafe { self.push_coerce(value, |value| value) }
+    }
+
+    /// Appends `value` and returns a reference to it, which stays valid across later pushes.
+    ///
+    /// # Safety
+    /// See [`PolyPtrVec::push_coerce`](crate::trait_vec::PolyPtrVec::push_coerce).
+    #[inline]
+    #[track_caller]
+    pub unsafe fn push_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &self,
+        mut value: U,
+        coerce: F,
+    ) -> &T {
+        let pointee = coerce_value(&mut value, coerce);
+        let segments = unsafe { &mut *self.segments.get() };
+        unsafe { &*segments.push_value(value, pointee) }
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.segments().element(index).map(|ptr| unsafe { &*ptr })
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.segments
+            .get_mut()
+            .element(index)
+            .map(|ptr| unsafe { &mut *ptr })
+    }
+
+    #[inline]
+    pub fn first(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    #[inline]
+    pub fn last(&self) -> Option<&T> {
+        self.get(self.len().wrapping_sub(1))
+    }
+
+    /// Iterates over the elements pushed before the iterator was created.
+    #[inline]
+    pub fn iter(&self) -> SegmentedIter<'_, T> {
+        SegmentedIter {
+            vec: self,
+            front: 0,
+            back: self.len(),
+        }
+    }
+
+    #[inline]
+    pub fn iter_mut(&mut self) -> SegmentedIterMut<'_, T> {
+        SegmentedIterMut::new(self.segments.get_mut().views())
+    }
+}
+
+#[cold]
+#[inline(never)]
+#[track_caller]
+fn index_out_of_bounds(index: usize, len: usize) -> ! {
+    panic!("index out of bounds: the len is {len} but the index is {index}");
+}
+
+impl<T: ?Sized> Index<usize> for SegmentedPolyVec<T> {
+    type Output = T;
+
+    #[inline]
+    #[track_caller]
+    fn index(&self, index: usize) -> &T {
+        match self.get(index) {
+            Some(value) => value,
+            None => index_out_of_bounds(index, self.len()),
+        }
+    }
+}
+
+impl<T: ?Sized> IndexMut<usize> for SegmentedPolyVec<T> {
+    #[inline]
+    #[track_caller]
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        let len = self.len();
+        match self.get_mut(index) {
+            Some(value) => value,
+            None => index_out_of_bounds(index, len),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> IntoIterator for &'a SegmentedPolyVec<T> {
+    type Item = &'a T;
+    type IntoIter = SegmentedIter<'a, T>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T: ?Sized> IntoIterator for &'a mut SegmentedPolyVec<T> {
+    type Item = &'a mut T;
+    type IntoIter = SegmentedIterMut<'a, T>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+/// Iterator over the elements of a [`SegmentedPolyVec`]. Elements pushed while it is alive
+/// aren't visited.
+pub struct SegmentedIter<'a, T: ?Sized> {
+    vec: &'a SegmentedPolyVec<T>,
+    front: usize,
+    back: usize,
+}
+
+impl<'a, T: ?Sized> Iterator for SegmentedIter<'a, T> {
+    type Item = &'a T;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        let value = unsafe { &*self.vec.segments().element_unchecked(self.front) };
+        self.front += 1;
+        Some(value)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.back - self.front;
+        (len, Some(len))
+    }
+}
+
+impl<T: ?Sized> DoubleEndedIterator for SegmentedIter<'_, T> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        self.back -= 1;
+        Some(unsafe { &*self.vec.segments().element_unchecked(self.back) })
+    }
+}
+
+impl<T: ?Sized> ExactSizeIterator for SegmentedIter<'_, T> {}
+
+impl<T: ?Sized> FusedIterator for SegmentedIter<'_, T> {}
+
+impl<T: ?Sized> Clone for SegmentedIter<'_, T> {
+    fn clone(&self) -> Self {
+        Self {
+            vec: self.vec,
+            front: self.front,
+            back: self.back,
+        }
+    }
+}
+
+/// Mutable iterator over the elements of a [`SegmentedPolyVec`].
+pub struct SegmentedIterMut<'a, T: ?Sized> {
+    iter: Iter<'a, SegmentView<T>>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SegmentedIterMut<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for SegmentedIterMut<'_, T> {}
+
+impl<'a, T: ?Sized> SegmentedIterMut<'a, T> {
+    #[inline]
+    pub(crate) fn new(views: &'a [SegmentView<T>]) -> Self {
+        Self {
+            iter: views.iter(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Iterator for SegmentedIterMut<'a, T> {
+    type Item = &'a mut T;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|view| unsafe { &mut *view.as_ptr() })
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<T: ?Sized> DoubleEndedIterator for SegmentedIterMut<'_, T> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next_back()
+            .map(|view| unsafe { &mut *view.as_ptr() })
+    }
+}
+
+impl<T: ?Sized> ExactSizeIterator for SegmentedIterMut<'_, T> {}
+
+impl<T: ?Sized> FusedIterator for SegmentedIterMut<'_, T> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::poly_push;
+    use crate::test_support::{assert_drops_once, Align64};
+    use std::fmt::Debug;
+
+    #[test]
+    fn references_survive_pushes() {
+        let vec = SegmentedPolyVec::<dyn Debug>::with_chunk_capacity(16);
+        let first = poly_push!(vec, 1u64);
+        let second = poly_push!(vec, String::from("two"));
+        let mut pushed = Vec::new();
+        for value in 0..100u32 {
+            pushed.push(poly_push!(vec, value));
+        }
+        let aligned = poly_push!(vec, Align64(3));
+
+        assert_eq!(format!("{:?}", first), "1");
+        assert_eq!(format!("{:?}", second), "\"two\"");
+        assert_eq!(format!("{:?}", pushed[99]), "99");
+        assert_eq!((aligned as *const dyn Debug).addr() % 64, 0);
+        assert!(ptr::eq(first, &vec[0]));
+        assert_eq!(vec.len(), 103);
+    }
+
+    #[test]
+    fn iteration_and_mutation() {
+        let mut vec = SegmentedPolyVec::<[u8]>::with_chunk_capacity(4);
+        poly_push!(vec, [1u8; 1]);
+        poly_push!(vec, [2u8; 2]);
+        poly_push!(vec, [3u8; 3]);
+        poly_push!(vec, [4u8; 4]);
+        poly_push!(vec, [5u8; 5]);
+        let lens: Vec<usize> = vec.iter().map(|value| value.len()).collect();
+        assert_eq!(lens, [1, 2, 3, 4, 5]);
+
+        let mut iter = vec.iter();
+        poly_push!(vec, [9u8; 2]);
+        assert_eq!(iter.len(), 5);
+        assert_eq!(iter.next_back().unwrap(), [5; 5]);
+
+        for value in vec.iter_mut() {
+            value[0] = 0;
+        }
+        vec[5][1] = 1;
+        assert_eq!(vec.get(5).unwrap(), [0, 1]);
+        assert_eq!(vec.first().unwrap(), [0]);
+        assert!(vec.get(6).is_none());
+    }
+
+    #[test]
+    fn drop_runs_destructors() {
+        assert_drops_once(|counter| {
+            let vec = SegmentedPolyVec::<dyn Debug>::with_chunk_capacity(8);
+            for _ in 0..10 {
+                poly_push!(vec, counter());
+            }
+            poly_push!(vec, ());
+            vec
+        });
+    }
+}
@@ -0,0 +1,631 @@
+//! Unsized values addressed by generational keys.
+
+use crate::allocator::Global;
+use crate::dyn_view_ptr::OffsetDynView;
+use crate::raw_buf::{align_up, RawPolyBuf};
+use crate::trait_vec::{box_value, coerce_value};
+use alloc::alloc::Layout;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::iter::{Enumerate, FusedIterator};
+use core::marker::PhantomData;
+#[cfg(feature = "nightly")]
+use core::marker::Unsize;
+use core::mem::size_of_val;
+use core::ops::{Index, IndexMut, Range};
+use core::ptr;
+use core::slice::Iter;
+
+/// Handle of a value in a [`PolySlotMap`].
+///
+/// A key stays valid until its value is removed. The slot may be reused afterwards,
+/// but with a new generation, so stale keys never reach the new value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    index: usize,
+    generation: u32,
+}
+
+impl Key {
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    #[inline]
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+struct Slot<T: ?Sized> {
+    generation: u32,
+    view: Option<OffsetDynView<T>>,
+}
+
+/// Byte buffer of the values, released when dropped.
+struct Buf(RawPolyBuf);
+
+impl Drop for Buf {
+    fn drop(&mut self) {
+        self.0.free(&Global)
+    }
+}
+
+/// Map of unsized values stored next to each other in one byte buffer, like in
+/// [`PolyPtrVec`](crate::trait_vec::PolyPtrVec), but addressed by [`Key`]s that survive
+/// the insertion and removal of other values.
+///
+/// Values never move once inserted. Removing one leaves a hole in the buffer,
+/// later insertions are placed into the first hole they fit into.
+pub struct PolySlotMap<T: ?Sized> {
+    buf: Buf,
+    slots: Vec<Slot<T>>,
+    /// Indices of the vacant slots
+    free_slots: Vec<usize>,
+    /// Number of slots whose generation ran out, they are never reused
+    retired: usize,
+    /// Unused byte ranges in front of the end of `buf`, neither empty nor adjacent to each other
+    holes: Vec<Range<usize>>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ?Sized> Default for PolySlotMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized> PolySlotMap<T> {
+    /// Evaluates the receiver of the `poly_*!` macros outside of their `unsafe` block.
+    #[doc(hidden)]
+    #[inline(always)]
+    pub fn __receiver(&mut self) -> &mut Self {
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            buf: Buf(RawPolyBuf::new()),
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            retired: 0,
+            holes: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free_slots.len() - self.retired
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of bytes in holes left by removed values.
+    #[inline]
+    pub fn free_bytes(&self) -> usize {
+        self.holes.iter().map(|hole| hole.len()).sum()
+    }
+
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub fn insert<U: Unsize<T>>(&mut self, value: U) -> Key {
+        // The closure returns its argument
+        unsafe { self.insert_coerce(value, |value| value) }
+    }
+
+    /// Inserts `value` and returns its key.
+    ///
+    /// # Safety
+    /// `coerce` has to return its argument, only coerced to `T`.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn insert_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &mut self,
+        mut value: U,
+        coerce: F,
+    ) -> Key {
+        let pointee = coerce_value(&mut value, coerce);
+        self.slots.reserve(1);
+        let offset = self.place(Layout::new::<U>());
+        unsafe { ptr::write(self.buf.0.as_mut_ptr().add(offset) as *mut U, value) };
+        let view = Some(OffsetDynView::from_ptr(offset as isize, pointee));
+
+        match self.free_slots.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.view = view;
+                Key {
+                    index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    view,
+                });
+                Key {
+                    index: self.slots.len() - 1,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    #[inline]
+    pub fn contains_key(&self, key: Key) -> bool {
+        self.view(key).is_some()
+    }
+
+    #[inline]
+    pub fn get(&self, key: Key) -> Option<&T> {
+        let view = self.view(key)?;
+        Some(unsafe { view.as_view(self.buf.0.as_ptr() as *const ()).into_inner() })
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        let base = self.buf.0.as_mut_ptr() as *mut ();
+        let view = self.view(key)?;
+        Some(unsafe { view.as_mut_view(base).into_inner_mut() })
+    }
+
+    /// Drops the value of `key`, returns whether there was one.
+    /// Use [`PolySlotMap::remove_boxed`] to take it out of the map instead.
+    pub fn remove(&mut self, key: Key) -> bool {
+        let Some(view) = self.vacate(key) else {
+            return false;
+        };
+        unsafe {
+            let value = view
+                .as_mut_view(self.buf.0.as_mut_ptr() as *mut ())
+                .inner()
+                .as_ptr();
+            let range =
+                view.offset as usize..view.offset as usize + Layout::for_value(&*value).size();
+            // The value is gone from the map before its destructor runs
+            self.release(range);
+            ptr::drop_in_place(value);
+        }
+        true
+    }
+
+    /// Removes the value of `key` and moves it into a new allocation.
+    pub fn remove_boxed(&mut self, key: Key) -> Option<Box<T>> {
+        let view = self.vacate(key)?;
+        unsafe {
+            let value = box_value(self.buf.0.as_mut_ptr(), &view);
+            self.release(view.offset as usize..view.offset as usize + size_of_val(&*value));
+            Some(value)
+        }
+    }
+
+    /// Iterates over the keys and values in slot order.
+    #[inline]
+    pub fn iter(&self) -> SlotIter<'_, T> {
+        SlotIter {
+            base: self.buf.0.as_ptr(),
+            slots: self.slots.iter().enumerate(),
+            remaining: self.len(),
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn iter_mut(&mut self) -> SlotIterMut<'_, T> {
+        SlotIterMut {
+            remaining: self.len(),
+            base: self.buf.0.as_mut_ptr(),
+            slots: self.slots.iter().enumerate(),
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn view(&self, key: Key) -> Option<&OffsetDynView<T>> {
+        match self.slots.get(key.index) {
+            Some(slot) if slot.generation == key.generation => slot.view.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Empties the slot of `key` and bumps its generation.
+    /// A slot that ran out of generations is retired, so stale keys never match again.
+    fn vacate(&mut self, key: Key) -> Option<OffsetDynView<T>> {
+        let slot = self.slots.get_mut(key.index)?;
+        if slot.generation != key.generation {
+            return None;
+        }
+        let view = slot.view.take()?;
+        match slot.generation.checked_add(1) {
+            Some(generation) => {
+                slot.generation = generation;
+                self.free_slots.push(key.index);
+            }
+            None => self.retired += 1,
+        }
+        Some(view)
+    }
+
+    /// Finds room for a value of `layout`, either in the first hole it fits into
+    /// or behind the end of `buf`, and returns its offset.
+    #[track_caller]
+    fn place(&mut self, layout: Layout) -> usize {
+        // Holes only fit values the buffer base is aligned for
+        if let Err(err) = self.buf.0.try_reserve(0, layout.align(), &Global) {
+            err.handle()
+        }
+        let found = self.holes.iter().enumerate().find_map(|(index, hole)| {
+            let offset = align_up(hole.start, layout.align());
+            (offset + layout.size() <= hole.end).then_some((index, offset))
+        });
+        if let Some((index, offset)) = found {
+            let hole = self.holes.swap_remove(index);
+            let end = offset + layout.size();
+            if hole.start < offset {
+                self.holes.push(hole.start..offset);
+            }
+            if end < hole.end {
+                self.holes.push(end..hole.end);
+            }
+            return offset;
+        }
+
+        let len = self.buf.0.len();
+        let offset = align_up(len, layout.align());
+        if let Err(err) =
+            self.buf
+                .0
+                .try_reserve(offset + layout.size() - len, layout.align(), &Global)
+        {
+            err.handle()
+        }
+        unsafe { self.buf.0.set_len(offset + layout.size()) };
+        offset
+    }
+
+    /// Records `range` as unused, merged with the holes next to it.
+    /// Called after [`PolySlotMap::vacate`], an emptied map starts over at the front of `buf`.
+    fn release(&mut self, mut range: Range<usize>) {
+        if self.is_empty() {
+            // Also drops the alignment padding no hole covers
+            self.holes.clear();
+            unsafe { self.buf.0.set_len(0) };
+            return;
+        }
+        self.holes.retain(|hole| {
+            if hole.end == range.start {
+                range.start = hole.start;
+                false
+            } else if hole.start == range.end {
+                range.end = hole.end;
+                false
+            } else {
+                true
+            }
+        });
+        if range.end >= self.buf.0.len() {
+            unsafe { self.buf.0.set_len(range.start) };
+        } else if !range.is_empty() {
+            self.holes.push(range);
+        }
+    }
+}
+
+impl<T: ?Sized> PolySlotMap<T> {
+    /// Drops every value, the rest still gets dropped if one of the destructors panics.
+    fn drop_values(&mut self) {
+        struct DropGuard<'a, T: ?Sized> {
+            base: *mut u8,
+            slots: Iter<'a, Slot<T>>,
+        }
+
+        impl<T: ?Sized> Drop for DropGuard<'_, T> {
+            fn drop(&mut self) {
+                for view in self.slots.by_ref().filter_map(|slot| slot.view.as_ref()) {
+                    unsafe {
+                        ptr::drop_in_place(view.as_mut_view(self.base as *mut ()).inner().as_ptr())
+                    }
+                }
+            }
+        }
+
+        let base = self.buf.0.as_mut_ptr();
+        let mut guard = DropGuard {
+            base,
+            slots: self.slots.iter(),
+        };
+        for view in guard.slots.by_ref().filter_map(|slot| slot.view.as_ref()) {
+            unsafe { ptr::drop_in_place(view.as_mut_view(base as *mut ()).inner().as_ptr()) }
+        }
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl<#[may_dangle] T: ?Sized> Drop for PolySlotMap<T> {
+    fn drop(&mut self) {
+        self.drop_values()
+    }
+}
+
+#[cfg(not(feature = "nightly"))]
+impl<T: ?Sized> Drop for PolySlotMap<T> {
+    fn drop(&mut self) {
+        self.drop_values()
+    }
+}
+
+impl<T: ?Sized> Index<Key> for PolySlotMap<T> {
+    type Output = T;
+
+    #[inline]
+    #[track_caller]
+    fn index(&self, key: Key) -> &T {
+        match self.get(key) {
+            Some(value) => value,
+            None => invalid_key(key),
+        }
+    }
+}
+
+impl<T: ?Sized> IndexMut<Key> for PolySlotMap<T> {
+    #[inline]
+    #[track_caller]
+    fn index_mut(&mut self, key: Key) -> &mut T {
+        match self.get_mut(key) {
+            Some(value) => value,
+            None => invalid_key(key),
+        }
+    }
+}
+
+#[cold]
+#[inline(never)]
+#[track_caller]
+fn invalid_key(key: Key) -> ! {
+    panic!("no value for {key:?}");
+}
+
+impl<'a, T: ?Sized> IntoIterator for &'a PolySlotMap<T> {
+    type Item = (Key, &'a T);
+    type IntoIter = SlotIter<'a, T>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T: ?Sized> IntoIterator for &'a mut PolySlotMap<T> {
+    type Item = (Key, &'a mut T);
+    type IntoIter = SlotIterMut<'a, T>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+/// Iterator over the keys and values of a [`PolySlotMap`].
+pub struct SlotIter<'a, T: ?Sized> {
+    base: *const u8,
+    slots: Enumerate<Iter<'a, Slot<T>>>,
+    remaining: usize,
+    _marker: PhantomData<&'a T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Send for SlotIter<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for SlotIter<'_, T> {}
+
+impl<'a, T: ?Sized> Iterator for SlotIter<'a, T> {
+    type Item = (Key, &'a T);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let base = self.base as *const ();
+        let (index, slot, view) = self
+            .slots
+            .find_map(|(index, slot)| Some((index, slot, slot.view.as_ref()?)))?;
+        self.remaining -= 1;
+        let key = Key {
+            index,
+            generation: slot.generation,
+        };
+        Some((key, unsafe { view.as_view(base).into_inner() }))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: ?Sized> ExactSizeIterator for SlotIter<'_, T> {}
+
+impl<T: ?Sized> FusedIterator for SlotIter<'_, T> {}
+
+/// Mutable iterator over the keys and values of a [`PolySlotMap`].
+pub struct SlotIterMut<'a, T: ?Sized> {
+    base: *mut u8,
+    slots: Enumerate<Iter<'a, Slot<T>>>,
+    remaining: usize,
+    _marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SlotIterMut<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for SlotIterMut<'_, T> {}
+
+impl<'a, T: ?Sized> Iterator for SlotIterMut<'a, T> {
+    type Item = (Key, &'a mut T);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let base = self.base as *mut ();
+        let (index, slot, view) = self
+            .slots
+            .find_map(|(index, slot)| Some((index, slot, slot.view.as_ref()?)))?;
+        self.remaining -= 1;
+        let key = Key {
+            index,
+            generation: slot.generation,
+        };
+        Some((key, unsafe { view.as_mut_view(base).into_inner_mut() }))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: ?Sized> ExactSizeIterator for SlotIterMut<'_, T> {}
+
+impl<T: ?Sized> FusedIterator for SlotIterMut<'_, T> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::poly_insert;
+    use crate::test_support::{Align64, DropCounter};
+    use std::cell::Cell;
+    use std::fmt::Debug;
+    use std::rc::Rc;
+
+    fn show(map: &PolySlotMap<dyn Debug>, key: Key) -> Option<String> {
+        map.get(key).map(|value| format!("{value:?}"))
+    }
+
+    #[test]
+    fn keys_survive_removal() {
+        let mut map = PolySlotMap::<dyn Debug>::new();
+        let one = poly_insert!(map, 1u8);
+        let two = poly_insert!(map, String::from("two"));
+        let three = poly_insert!(map, 3u64);
+        assert_eq!(map.len(), 3);
+
+        assert!(map.remove(two));
+        assert!(!map.remove(two));
+        assert_eq!(show(&map, one).unwrap(), "1");
+        assert_eq!(show(&map, three).unwrap(), "3");
+        assert!(show(&map, two).is_none());
+
+        // The slot is reused with a new generation
+        let four = poly_insert!(map, 4u16);
+        assert_eq!(four.index(), two.index());
+        assert_ne!(four.generation(), two.generation());
+        assert!(show(&map, two).is_none());
+        assert!(!map.contains_key(two));
+        assert_eq!(format!("{:?}", &map[four]), "4");
+
+        assert_eq!(format!("{:?}", map.remove_boxed(one).unwrap()), "1");
+        assert!(map.remove_boxed(one).is_none());
+        let keys: Vec<Key> = map.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, [four, three]);
+    }
+
+    #[test]
+    fn holes_are_reused() {
+        let mut map = PolySlotMap::<[u8]>::new();
+        let a = poly_insert!(map, [1u8; 16]);
+        let b = poly_insert!(map, [2u8; 16]);
+        let c = poly_insert!(map, [3u8; 16]);
+        let end = map.buf.0.len();
+        map.remove(a);
+        map.remove(b);
+        assert_eq!(map.free_bytes(), 32);
+
+        // Both halves of the merged hole are filled without growing the buffer
+        let d = poly_insert!(map, [4u8; 8]);
+        let e = poly_insert!(map, [5u8; 24]);
+        assert_eq!(map.free_bytes(), 0);
+        assert_eq!(map.buf.0.len(), end);
+        assert_eq!(&map[c], [3; 16]);
+        assert_eq!(&map[d], [4; 8]);
+        map[e][0] = 0;
+        assert_eq!(map[e][..2], [0, 5]);
+
+        // Removing the last value shrinks the buffer instead of leaving a hole
+        map.remove(c);
+        assert_eq!(map.free_bytes(), 0);
+        assert_eq!(map.buf.0.len(), 32);
+    }
+
+    #[test]
+    fn reused_holes_stay_aligned() {
+        let mut map = PolySlotMap::<dyn Debug>::new();
+        let small = poly_insert!(map, [1u8; 100]);
+        poly_insert!(map, 2u8);
+        map.remove(small);
+        let aligned = poly_insert!(map, Align64(3));
+        assert_eq!((&map[aligned] as *const dyn Debug).addr() % 64, 0);
+        for (_, value) in map.iter_mut() {
+            assert_eq!(
+                (value as *const dyn Debug).addr() % std::mem::align_of_val(value),
+                0
+            );
+        }
+        assert_eq!(map.iter().len(), 2);
+    }
+
+    #[test]
+    fn exhausted_slots_are_retired() {
+        let mut map = PolySlotMap::<dyn Debug>::new();
+        let old = poly_insert!(map, 1u8);
+        map.slots[old.index()].generation = u32::MAX;
+        let old = Key {
+            generation: u32::MAX,
+            ..old
+        };
+        assert!(map.remove(old));
+        assert!(map.is_empty());
+        assert_eq!(map.slots[old.index()].generation, u32::MAX);
+
+        // The slot isn't handed out again, so `old` can't reach the new value
+        let new = poly_insert!(map, 2u8);
+        assert_ne!(new.index(), old.index());
+        assert_eq!(map.len(), 1);
+        assert!(!map.contains_key(old));
+        assert!(!map.remove(old));
+        assert_eq!(map.iter().len(), 1);
+    }
+
+    #[test]
+    fn emptied_map_reclaims_padding() {
+        let mut map = PolySlotMap::<dyn Debug>::new();
+        let small = poly_insert!(map, 1u8);
+        let aligned = poly_insert!(map, Align64(2));
+        map.remove(aligned);
+        // The padding in front of the removed value isn't a hole
+        assert_eq!(map.buf.0.len(), 64);
+        map.remove(small);
+        assert_eq!(map.buf.0.len(), 0);
+        assert_eq!(map.free_bytes(), 0);
+        let key = poly_insert!(map, Align64(3));
+        assert_eq!(map.buf.0.len(), 64);
+        assert_eq!(show(&map, key).unwrap(), "Align64(3)");
+    }
+
+    #[test]
+    fn remove_and_drop_run_destructors() {
+        let drops = Rc::new(Cell::new(0));
+        let mut map = PolySlotMap::<dyn Debug>::new();
+        let keys: Vec<Key> = (0..4)
+            .map(|_| poly_insert!(map, DropCounter::new(&drops, "key")))
+            .collect();
+        map.remove(keys[1]);
+        assert_eq!(drops.get(), 1);
+        drop(map.remove_boxed(keys[2]));
+        assert_eq!(drops.get(), 2);
+        drop(map);
+        assert_eq!(drops.get(), 4);
+    }
+}
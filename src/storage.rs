@@ -0,0 +1,612 @@
+//! Storage backends of [`PolyVec`](crate::trait_vec::PolyVec).
+//!
+//! A storage owns the byte buffer the elements live in and the table of their views.
+//! The algorithms of [`PolyVec`](crate::trait_vec::PolyVec) only go through
+//! the sealed [`PolyStorage`] trait, so every backend behaves the same.
+
+use crate::allocator::{Allocator, Global};
+use crate::dyn_view_ptr::OffsetDynView;
+use crate::raw_buf::{FreeList, RawPolyBuf, ReserveError, ViewTable};
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::slice;
+
+/// Storage of a [`PolyVec`](crate::trait_vec::PolyVec), implemented by the backends of this crate.
+pub trait PolyStorage<T: ?Sized>: RawStorage<T> {}
+
+pub(crate) use self::raw::RawStorage;
+
+mod raw {
+    use super::*;
+
+    /// Primitive operations of a storage. The byte buffer keeps its base aligned to
+    /// [`RawStorage::buf_align`], which is at least the largest alignment reserved so far.
+    pub trait RawStorage<T: ?Sized> {
+        /// Allocator of the free list and of temporary copies of the byte buffer.
+        type Alloc: Allocator;
+
+        fn buf_ptr(&self) -> *const u8;
+
+        fn buf_mut_ptr(&mut self) -> *mut u8;
+
+        fn buf_len(&self) -> usize;
+
+        /// # Safety
+        /// `len` must not exceed the capacity and the bytes up to it must be initialized.
+        unsafe fn set_buf_len(&mut self, len: usize);
+
+        fn buf_capacity(&self) -> usize;
+
+        fn buf_align(&self) -> usize;
+
+        /// Alignment the base of the buffer has without moving it,
+        /// at least [`RawStorage::buf_align`].
+        #[inline(always)]
+        fn base_align(&self) -> usize {
+            self.buf_align()
+        }
+
+        /// Makes room for `additional` bytes behind the end of the buffer,
+        /// aligning the buffer to `align` if it isn't already.
+        fn try_reserve_buf(
+            &mut self,
+            additional: usize,
+            align: usize,
+            exact: bool,
+        ) -> Result<(), ReserveError>;
+
+        fn shrink_buf_to(&mut self, min_capacity: usize);
+
+        fn view_ptr(&self) -> *const OffsetDynView<T>;
+
+        fn view_mut_ptr(&mut self) -> *mut OffsetDynView<T>;
+
+        fn view_len(&self) -> usize;
+
+        /// # Safety
+        /// `len` must not exceed the capacity and the views up to it must be initialized.
+        unsafe fn set_view_len(&mut self, len: usize);
+
+        fn try_reserve_views(&mut self, additional: usize) -> Result<(), ReserveError>;
+
+        /// Holes in the byte buffer, kept by the storage so they are released with it.
+        /// `None` if the storage can't allocate a free list.
+        fn free_list(&self) -> Option<&FreeList>;
+
+        /// [`RawStorage::free_list`] together with the allocator it grows in.
+        fn free_list_mut(&mut self) -> Option<(&mut FreeList, &Self::Alloc)>;
+
+        #[inline]
+        fn views(&self) -> &[OffsetDynView<T>] {
+            unsafe { slice::from_raw_parts(self.view_ptr(), self.view_len()) }
+        }
+
+        #[inline]
+        fn views_mut(&mut self) -> &mut [OffsetDynView<T>] {
+            unsafe { slice::from_raw_parts_mut(self.view_mut_ptr(), self.view_len()) }
+        }
+
+        #[inline]
+        #[track_caller]
+        fn reserve_views(&mut self, additional: usize) {
+            if let Err(err) = self.try_reserve_views(additional) {
+                err.handle()
+            }
+        }
+
+        #[inline]
+        #[track_caller]
+        fn reserve_buf(&mut self, additional: usize, align: usize) {
+            if let Err(err) = self.try_reserve_buf(additional, align, false) {
+                err.handle()
+            }
+        }
+
+        /// Appends `view`, room for it has to be reserved by the caller.
+        #[inline]
+        fn push_view(&mut self, view: OffsetDynView<T>) {
+            let len = self.view_len();
+            unsafe {
+                ptr::write(self.view_mut_ptr().add(len), view);
+                self.set_view_len(len + 1);
+            }
+        }
+
+        /// Inserts `view` at `index`, room for it has to be reserved by the caller.
+        #[inline]
+        fn insert_view(&mut self, index: usize, view: OffsetDynView<T>) {
+            let len = self.view_len();
+            debug_assert!(index <= len);
+            unsafe {
+                let ptr = self.view_mut_ptr().add(index);
+                ptr::copy(ptr, ptr.add(1), len - index);
+                ptr::write(ptr, view);
+                self.set_view_len(len + 1);
+            }
+        }
+
+        /// Removes the view at `index`, which must be less than the length.
+        #[inline]
+        fn remove_view(&mut self, index: usize) -> OffsetDynView<T> {
+            let len = self.view_len();
+            debug_assert!(index < len);
+            unsafe {
+                let ptr = self.view_mut_ptr().add(index);
+                let view = ptr::read(ptr);
+                ptr::copy(ptr.add(1), ptr, len - index - 1);
+                self.set_view_len(len - 1);
+                view
+            }
+        }
+
+        #[inline]
+        fn pop_view(&mut self) -> Option<OffsetDynView<T>> {
+            let len = self.view_len().checked_sub(1)?;
+            unsafe {
+                self.set_view_len(len);
+                Some(ptr::read(self.view_ptr().add(len)))
+            }
+        }
+    }
+}
+
+/// Heap storage of [`PolyPtrVec`](crate::trait_vec::PolyPtrVec), allocated in `A`.
+pub struct HeapStorage<T: ?Sized, A: Allocator = Global> {
+    view: ViewTable<T>,
+    buf: RawPolyBuf,
+    holes: FreeList,
+    alloc: A,
+}
+
+impl<T: ?Sized, A: Allocator> HeapStorage<T, A> {
+    #[inline]
+    pub(crate) const fn new_in(alloc: A) -> Self {
+        Self {
+            view: ViewTable::new(),
+            buf: RawPolyBuf::new(),
+            holes: FreeList::new(),
+            alloc,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn with_capacity_in(count: usize, bytes: usize, align: usize, alloc: A) -> Self {
+        Self {
+            view: ViewTable::with_capacity(count, &alloc),
+            buf: RawPolyBuf::with_capacity(bytes, align, &alloc),
+            holes: FreeList::new(),
+            alloc,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn allocator(&self) -> &A {
+        &self.alloc
+    }
+}
+
+impl<T: ?Sized, A: Allocator> RawStorage<T> for HeapStorage<T, A> {
+    type Alloc = A;
+
+    #[inline(always)]
+    fn buf_ptr(&self) -> *const u8 {
+        self.buf.as_ptr()
+    }
+
+    #[inline(always)]
+    fn buf_mut_ptr(&mut self) -> *mut u8 {
+        self.buf.as_mut_ptr()
+    }
+
+    #[inline(always)]
+    fn buf_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    #[inline(always)]
+    unsafe fn set_buf_len(&mut self, len: usize) {
+        self.buf.set_len(len)
+    }
+
+    #[inline(always)]
+    fn buf_capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    #[inline(always)]
+    fn buf_align(&self) -> usize {
+        self.buf.align()
+    }
+
+    #[inline]
+    fn try_reserve_buf(
+        &mut self,
+        additional: usize,
+        align: usize,
+        exact: bool,
+    ) -> Result<(), ReserveError> {
+        if exact {
+            self.buf.try_reserve_exact(additional, align, &self.alloc)
+        } else {
+            self.buf.try_reserve(additional, align, &self.alloc)
+        }
+    }
+
+    #[inline]
+    fn shrink_buf_to(&mut self, min_capacity: usize) {
+        self.buf.shrink_to(min_capacity, &self.alloc)
+    }
+
+    #[inline(always)]
+    fn view_ptr(&self) -> *const OffsetDynView<T> {
+        self.view.as_ptr()
+    }
+
+    #[inline(always)]
+    fn view_mut_ptr(&mut self) -> *mut OffsetDynView<T> {
+        self.view.as_mut_ptr()
+    }
+
+    #[inline(always)]
+    fn view_len(&self) -> usize {
+        self.view.len()
+    }
+
+    #[inline(always)]
+    unsafe fn set_view_len(&mut self, len: usize) {
+        self.view.set_len(len)
+    }
+
+    #[inline]
+    fn try_reserve_views(&mut self, additional: usize) -> Result<(), ReserveError> {
+        self.view.try_reserve(additional, &self.alloc)
+    }
+
+    #[inline(always)]
+    fn free_list(&self) -> Option<&FreeList> {
+        Some(&self.holes)
+    }
+
+    #[inline(always)]
+    fn free_list_mut(&mut self) -> Option<(&mut FreeList, &A)> {
+        Some((&mut self.holes, &self.alloc))
+    }
+}
+
+impl<T: ?Sized, A: Allocator> PolyStorage<T> for HeapStorage<T, A> {}
+
+impl<T: ?Sized, A: Allocator> HeapStorage<T, A> {
+    fn free(&mut self) {
+        self.view.free(&self.alloc);
+        self.buf.free(&self.alloc);
+        self.holes.free(&self.alloc);
+    }
+}
+
+// Only the allocations are released, the elements are dropped by the vec.
+#[cfg(feature = "nightly")]
+unsafe impl<#[may_dangle] T: ?Sized, A: Allocator> Drop for HeapStorage<T, A> {
+    fn drop(&mut self) {
+        self.free()
+    }
+}
+
+#[cfg(not(feature = "nightly"))]
+impl<T: ?Sized, A: Allocator> Drop for HeapStorage<T, A> {
+    fn drop(&mut self) {
+        self.free()
+    }
+}
+
+/// Alignment of the byte buffer of [`InlineStorage`], elements with a larger one are rejected.
+pub const INLINE_ALIGN: usize = 16;
+
+#[repr(C, align(16))]
+struct InlineBytes<const BYTES: usize>([MaybeUninit<u8>; BYTES]);
+
+/// Storage of [`InlinePolyVec`](crate::trait_vec::InlinePolyVec), `BYTES` bytes of elements
+/// and up to `N` views kept inside the vec itself.
+pub struct InlineStorage<T: ?Sized, const BYTES: usize, const N: usize> {
+    view: [MaybeUninit<OffsetDynView<T>>; N],
+    view_len: usize,
+    buf: InlineBytes<BYTES>,
+    len: usize,
+    /// Largest alignment stored so far, the tail only moves by multiples of it
+    align: usize,
+}
+
+impl<T: ?Sized, const BYTES: usize, const N: usize> InlineStorage<T, BYTES, N> {
+    #[inline]
+    pub(crate) const fn new() -> Self {
+        Self {
+            view: [const { MaybeUninit::uninit() }; N],
+            view_len: 0,
+            buf: InlineBytes([MaybeUninit::uninit(); BYTES]),
+            len: 0,
+            align: 1,
+        }
+    }
+}
+
+impl<T: ?Sized, const BYTES: usize, const N: usize> RawStorage<T> for InlineStorage<T, BYTES, N> {
+    type Alloc = Global;
+
+    #[inline(always)]
+    fn buf_ptr(&self) -> *const u8 {
+        self.buf.0.as_ptr() as *const u8
+    }
+
+    #[inline(always)]
+    fn buf_mut_ptr(&mut self) -> *mut u8 {
+        self.buf.0.as_mut_ptr() as *mut u8
+    }
+
+    #[inline(always)]
+    fn buf_len(&self) -> usize {
+        self.len
+    }
+
+    #[inline(always)]
+    unsafe fn set_buf_len(&mut self, len: usize) {
+        debug_assert!(len <= BYTES);
+        self.len = len;
+    }
+
+    #[inline(always)]
+    fn buf_capacity(&self) -> usize {
+        BYTES
+    }
+
+    #[inline(always)]
+    fn buf_align(&self) -> usize {
+        self.align
+    }
+
+    #[inline(always)]
+    fn base_align(&self) -> usize {
+        INLINE_ALIGN
+    }
+
+    #[inline]
+    fn try_reserve_buf(
+        &mut self,
+        additional: usize,
+        align: usize,
+        _exact: bool,
+    ) -> Result<(), ReserveError> {
+        if align > INLINE_ALIGN || BYTES - self.len < additional {
+            return Err(ReserveError::CapacityOverflow);
+        }
+        self.align = self.align.max(align);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn shrink_buf_to(&mut self, _min_capacity: usize) {}
+
+    #[inline(always)]
+    fn view_ptr(&self) -> *const OffsetDynView<T> {
+        self.view.as_ptr() as *const OffsetDynView<T>
+    }
+
+    #[inline(always)]
+    fn view_mut_ptr(&mut self) -> *mut OffsetDynView<T> {
+        self.view.as_mut_ptr() as *mut OffsetDynView<T>
+    }
+
+    #[inline(always)]
+    fn view_len(&self) -> usize {
+        self.view_len
+    }
+
+    #[inline(always)]
+    unsafe fn set_view_len(&mut self, len: usize) {
+        debug_assert!(len <= N);
+        self.view_len = len;
+    }
+
+    #[inline]
+    fn try_reserve_views(&mut self, additional: usize) -> Result<(), ReserveError> {
+        if N - self.view_len < additional {
+            return Err(ReserveError::CapacityOverflow);
+        }
+        Ok(())
+    }
+
+    /// The inline storage never allocates, so it has no free list.
+    #[inline(always)]
+    fn free_list(&self) -> Option<&FreeList> {
+        None
+    }
+
+    #[inline(always)]
+    fn free_list_mut(&mut self) -> Option<(&mut FreeList, &Global)> {
+        None
+    }
+}
+
+impl<T: ?Sized, const BYTES: usize, const N: usize> PolyStorage<T> for InlineStorage<T, BYTES, N> {}
+
+/// Storage of [`SmallPolyVec`](crate::trait_vec::SmallPolyVec), inline until the elements
+/// outgrow `BYTES` bytes, `N` views or [`INLINE_ALIGN`], then on the heap for good.
+pub struct SmallStorage<T: ?Sized, const BYTES: usize, const N: usize> {
+    data: SmallData<T, BYTES, N>,
+}
+
+enum SmallData<T: ?Sized, const BYTES: usize, const N: usize> {
+    Inline(InlineStorage<T, BYTES, N>),
+    Heap(HeapStorage<T>),
+}
+
+/// Runs `$body` on whichever storage is active.
+macro_rules! dispatch {
+    ($data:expr, $storage:ident => $body:expr) => {
+        match $data {
+            SmallData::Inline($storage) => $body,
+            SmallData::Heap($storage) => $body,
+        }
+    };
+}
+
+impl<T: ?Sized, const BYTES: usize, const N: usize> SmallStorage<T, BYTES, N> {
+    #[inline]
+    pub(crate) const fn new() -> Self {
+        Self {
+            data: SmallData::Inline(InlineStorage::new()),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn spilled(&self) -> bool {
+        matches!(self.data, SmallData::Heap(_))
+    }
+
+    /// Moves the bytes and views to the heap, with room for `bytes` more bytes aligned
+    /// to `align` and `views` more views. Offsets stay valid because the heap buffer
+    /// is aligned to at least the alignment the inline one was used with.
+    #[cold]
+    fn spill(&mut self, bytes: usize, align: usize, views: usize) -> Result<(), ReserveError> {
+        let SmallData::Inline(inline) = &mut self.data else {
+            return Ok(());
+        };
+        let buf_len = inline.buf_len();
+        let view_len = inline.view_len();
+        let mut heap = HeapStorage::new_in(Global);
+        heap.try_reserve_buf(
+            buf_len
+                .checked_add(bytes)
+                .ok_or(ReserveError::CapacityOverflow)?
+                .max(BYTES.saturating_mul(2)),
+            align.max(inline.buf_align()),
+            true,
+        )?;
+        heap.try_reserve_views(
+            view_len
+                .checked_add(views)
+                .ok_or(ReserveError::CapacityOverflow)?
+                .max(N.saturating_mul(2)),
+        )?;
+        unsafe {
+            ptr::copy_nonoverlapping(inline.buf_ptr(), heap.buf_mut_ptr(), buf_len);
+            ptr::copy_nonoverlapping(inline.view_ptr(), heap.view_mut_ptr(), view_len);
+            heap.set_buf_len(buf_len);
+            heap.set_view_len(view_len);
+            // The values now belong to `heap`, the inline storage never drops anything itself
+            inline.set_view_len(0);
+        }
+        self.data = SmallData::Heap(heap);
+        Ok(())
+    }
+}
+
+impl<T: ?Sized, const BYTES: usize, const N: usize> RawStorage<T> for SmallStorage<T, BYTES, N> {
+    type Alloc = Global;
+
+    #[inline(always)]
+    fn buf_ptr(&self) -> *const u8 {
+        dispatch!(&self.data, storage => storage.buf_ptr())
+    }
+
+    #[inline(always)]
+    fn buf_mut_ptr(&mut self) -> *mut u8 {
+        dispatch!(&mut self.data, storage => storage.buf_mut_ptr())
+    }
+
+    #[inline(always)]
+    fn buf_len(&self) -> usize {
+        dispatch!(&self.data, storage => storage.buf_len())
+    }
+
+    #[inline(always)]
+    unsafe fn set_buf_len(&mut self, len: usize) {
+        dispatch!(&mut self.data, storage => storage.set_buf_len(len))
+    }
+
+    #[inline(always)]
+    fn buf_capacity(&self) -> usize {
+        dispatch!(&self.data, storage => storage.buf_capacity())
+    }
+
+    #[inline(always)]
+    fn buf_align(&self) -> usize {
+        dispatch!(&self.data, storage => storage.buf_align())
+    }
+
+    #[inline(always)]
+    fn base_align(&self) -> usize {
+        dispatch!(&self.data, storage => storage.base_align())
+    }
+
+    #[inline]
+    fn try_reserve_buf(
+        &mut self,
+        additional: usize,
+        align: usize,
+        exact: bool,
+    ) -> Result<(), ReserveError> {
+        match &mut self.data {
+            SmallData::Inline(inline) => {
+                if inline.try_reserve_buf(additional, align, exact).is_err() {
+                    self.spill(additional, align, 0)?;
+                }
+                Ok(())
+            }
+            SmallData::Heap(heap) => heap.try_reserve_buf(additional, align, exact),
+        }
+    }
+
+    /// Heap buffers are shrunk in place, the elements never move back inline.
+    #[inline]
+    fn shrink_buf_to(&mut self, min_capacity: usize) {
+        dispatch!(&mut self.data, storage => storage.shrink_buf_to(min_capacity))
+    }
+
+    #[inline(always)]
+    fn view_ptr(&self) -> *const OffsetDynView<T> {
+        dispatch!(&self.data, storage => storage.view_ptr())
+    }
+
+    #[inline(always)]
+    fn view_mut_ptr(&mut self) -> *mut OffsetDynView<T> {
+        dispatch!(&mut self.data, storage => storage.view_mut_ptr())
+    }
+
+    #[inline(always)]
+    fn view_len(&self) -> usize {
+        dispatch!(&self.data, storage => storage.view_len())
+    }
+
+    #[inline(always)]
+    unsafe fn set_view_len(&mut self, len: usize) {
+        dispatch!(&mut self.data, storage => storage.set_view_len(len))
+    }
+
+    #[inline]
+    fn try_reserve_views(&mut self, additional: usize) -> Result<(), ReserveError> {
+        match &mut self.data {
+            SmallData::Inline(inline) => {
+                if inline.try_reserve_views(additional).is_err() {
+                    self.spill(0, 1, additional)?;
+                }
+                Ok(())
+            }
+            SmallData::Heap(heap) => heap.try_reserve_views(additional),
+        }
+    }
+
+    #[inline(always)]
+    fn free_list(&self) -> Option<&FreeList> {
+        dispatch!(&self.data, storage => storage.free_list())
+    }
+
+    /// Moves the elements to the heap first, where the free list is kept.
+    #[inline]
+    fn free_list_mut(&mut self) -> Option<(&mut FreeList, &Global)> {
+        if let Err(err) = self.spill(0, 1, 0) {
+            err.handle()
+        }
+        dispatch!(&mut self.data, storage => storage.free_list_mut())
+    }
+}
+
+impl<T: ?Sized, const BYTES: usize, const N: usize> PolyStorage<T> for SmallStorage<T, BYTES, N> {}
@@ -0,0 +1,50 @@
+//! Fixtures shared by the unit tests of the containers.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Counts its drops in a shared counter.
+#[derive(Debug)]
+pub struct DropCounter {
+    drops: Rc<Cell<usize>>,
+    pub name: String,
+}
+
+impl DropCounter {
+    pub fn new(drops: &Rc<Cell<usize>>, name: &str) -> Self {
+        Self {
+            drops: drops.clone(),
+            name: name.to_string(),
+        }
+    }
+}
+
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        self.drops.set(self.drops.get() + 1);
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+#[repr(align(16))]
+pub struct Align16(pub u8);
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+#[repr(align(64))]
+pub struct Align64(pub u16);
+
+/// Fills a container with the counters `counter` hands out and checks that
+/// dropping it drops each of them exactly once.
+pub fn assert_drops_once<C>(fill: impl FnOnce(&mut dyn FnMut() -> DropCounter) -> C) {
+    let drops = Rc::new(Cell::new(0));
+    let mut made = 0;
+    let container = fill(&mut || {
+        made += 1;
+        DropCounter::new(&drops, "counter")
+    });
+    assert_eq!(drops.get(), 0);
+    drop(container);
+    assert_eq!(drops.get(), made);
+}
@@ -1,430 +1,4409 @@
+use crate::allocator::{Allocator, Global};
 use crate::dyn_view_ptr::OffsetDynView;
-use std::collections::TryReserveError;
-use std::marker::Unsize;
-use std::mem::{align_of, size_of};
-use std::ptr;
-use std::slice::Iter;
+use crate::raw_buf::{align_up, FreeList, RawPolyBuf, ReserveError};
+use crate::storage::{HeapStorage, InlineStorage, PolyStorage, RawStorage, SmallStorage};
+use alloc::alloc::{self as heap, Layout};
+use alloc::boxed::Box;
+use alloc::collections::TryReserveError;
+use alloc::vec::Vec;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+#[cfg(feature = "nightly")]
+use core::marker::Unsize;
+use core::mem::{self, align_of, align_of_val, size_of, size_of_val};
+use core::ops::{Bound, Index, IndexMut, Range, RangeBounds};
+use core::ptr;
+use core::slice::{self, Iter};
 
 pub struct OffsettingIter<'a, T: ?Sized + 'a> {
-    ref_to_vec: &'a PolyPtrVec<T>,
+    base: *const u8,
     iter: Iter<'a, OffsetDynView<T>>,
+    /// Number of live elements left in `iter`
+    remaining: usize,
+    _marker: PhantomData<&'a T>,
 }
 
+unsafe impl<T: ?Sized + Sync> Send for OffsettingIter<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for OffsettingIter<'_, T> {}
+
 impl<'a, T: ?Sized> OffsettingIter<'a, T> {
     #[inline]
-    pub fn new(poly_vec: &'a PolyPtrVec<T>) -> OffsettingIter<'a, T> {
+    pub fn new<S: PolyStorage<T>>(poly_vec: &'a PolyVec<T, S>) -> OffsettingIter<'a, T> {
         Self {
-            ref_to_vec: poly_vec,
-            iter: poly_vec.view.iter(),
+            base: poly_vec.storage.buf_ptr(),
+            iter: poly_vec.storage.views().iter(),
+            remaining: poly_vec.len(),
+            _marker: PhantomData,
         }
     }
 }
 
+impl<'a, T: ?Sized + 'a> OffsettingIter<'a, T> {
+    #[inline(always)]
+    fn offset_view(&self, view_offset: &OffsetDynView<T>) -> &'a T {
+        unsafe { view_offset.as_view(self.base as *const ()).into_inner() }
+    }
+}
+
 impl<'a, T: ?Sized + 'a> Iterator for OffsettingIter<'a, T> {
     type Item = &'a T;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|view_offset| unsafe {
-            view_offset
-                .as_view(self.ref_to_vec.buf.as_ptr().to_raw_parts().0)
-                .into_inner()
-        })
+        let view_offset = self.iter.find(|view| !view.is_dead())?;
+        self.remaining -= 1;
+        Some(self.offset_view(view_offset))
     }
 
     #[inline]
     fn size_hint(&self) -> (usize, Option<usize>) {
-        self.iter.size_hint()
+        (self.remaining, Some(self.remaining))
     }
-}
-
-pub struct PolyPtrVec<T: ?Sized> {
-    view: Vec<OffsetDynView<T>>,
-    buf: Vec<u8>,
-}
 
-impl<T: ?Sized> PolyPtrVec<T> {
     #[inline]
-    fn push_value<U: Unsize<T>>(&mut self, mut value: U) -> OffsetDynView<T> {
-        let needed_space = aligned_size_of::<U>();
-        let ptr = unsafe {
-            let ptr = self.buf.as_ptr();
-            let new_ptr = ptr.add(self.buf.len());
-            let pointee = &mut value as &mut T as *mut T;
-            let info = pointee.to_raw_parts();
-            ptr::write(new_ptr as *mut U, value);
-            self.buf.set_len(self.buf.len() + needed_space);
-            ptr::from_raw_parts::<T>(new_ptr as *const (), info.1).cast_mut()
-        };
-        let offset = unsafe { self.buf.as_ptr().offset_from(ptr as *const u8) };
-        OffsetDynView::<T>::from_ptr(offset, ptr)
+    fn count(self) -> usize {
+        self.remaining
     }
 
     #[inline]
-    fn insert_element<U: Unsize<T>>(&mut self, index: usize, element: U) -> OffsetDynView<T> {
-        let needed_space = aligned_size_of::<U>();
-        let view_len = self.view.len();
-        #[cold]
-        #[inline(never)]
-        fn assert_failed(index: usize, len: usize) -> ! {
-            panic!("insertion index (is {index}) should be <= len (is {len})");
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        if self.remaining == self.iter.len() {
+            // No tombstones left, the views can be indexed directly
+            let view_offset = self.iter.nth(n);
+            self.remaining = self.iter.len();
+            return view_offset.map(|view_offset| self.offset_view(view_offset));
         }
+        for _ in 0..n {
+            self.next()?;
+        }
+        self.next()
+    }
 
-        let ptr = unsafe {
-            let ptr = match self.view.get(index) {
-                None => {
-                    if index == view_len {
-                        self.buf.as_mut_ptr().add(self.buf.len())
-                    } else {
-                        assert_failed(index, view_len);
-                    }
-                }
-                Some(view) => {
-                    let src = self.buf.as_mut_ptr().offset(-view.offset);
-                    let dst = src.add(needed_space);
-                    let count = (self.buf.len() as isize + view.offset) as usize;
-
-                    ptr::copy(src, dst, count);
-                    src
-                }
-            } as *mut U;
-            ptr::write(ptr, element);
-            self.buf.set_len(self.buf.len() + needed_space);
-            ptr
-        };
+    #[inline]
+    fn last(mut self) -> Option<Self::Item> {
+        self.next_back()
+    }
+}
 
-        let offset = unsafe { self.buf.as_ptr().offset_from(ptr as *const u8) };
-        OffsetDynView::<T>::from_ptr(offset, ptr)
+impl<'a, T: ?Sized + 'a> DoubleEndedIterator for OffsettingIter<'a, T> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let view_offset = self.iter.rfind(|view| !view.is_dead())?;
+        self.remaining -= 1;
+        Some(self.offset_view(view_offset))
     }
 
-    #[track_caller]
-    fn remove_element(&mut self, index: usize) -> usize {
-        #[cold]
-        #[inline(never)]
-        #[track_caller]
-        fn assert_failed(index: usize, len: usize) -> ! {
-            panic!("removal index (is {index}) should be < len (is {len})");
+    #[inline]
+    fn nth_back(&mut self, n: usize) -> Option<Self::Item> {
+        if self.remaining == self.iter.len() {
+            let view_offset = self.iter.nth_back(n);
+            self.remaining = self.iter.len();
+            return view_offset.map(|view_offset| self.offset_view(view_offset));
         }
+        for _ in 0..n {
+            self.next_back()?;
+        }
+        self.next_back()
+    }
+}
 
-        match self.view.get(index) {
-            None => assert_failed(index, self.view.len()),
-            Some(view) => {
-                let next_offset = if let Some(next_view) = self.view.get(index + 1) {
-                    next_view.offset
-                } else {
-                    self.buf.len() as isize
-                };
+impl<'a, T: ?Sized + 'a> ExactSizeIterator for OffsettingIter<'a, T> {}
 
-                let size = (next_offset - view.offset) as usize;
-                unsafe {
-                    let ptr = self.buf.as_mut_ptr().offset(view.offset);
-                    ptr::copy(
-                        ptr.add(size),
-                        ptr,
-                        self.buf.len() - view.offset as usize - size,
-                    );
-                }
-                size
-            }
+impl<'a, T: ?Sized + 'a> FusedIterator for OffsettingIter<'a, T> {}
+
+impl<T: ?Sized> Clone for OffsettingIter<'_, T> {
+    fn clone(&self) -> Self {
+        Self {
+            base: self.base,
+            iter: self.iter.clone(),
+            remaining: self.remaining,
+            _marker: PhantomData,
         }
     }
 }
 
-fn aligned_size_of<U>() -> usize {
-    size_of::<U>() + align_of::<U>()
+pub struct OffsettingIterMut<'a, T: ?Sized + 'a> {
+    base: *mut u8,
+    iter: Iter<'a, OffsetDynView<T>>,
+    /// Number of live elements left in `iter`
+    remaining: usize,
+    _marker: PhantomData<&'a mut T>,
 }
 
-impl<T: ?Sized> Default for PolyPtrVec<T> {
-    fn default() -> Self {
-        PolyPtrVec::<T>::new()
-    }
-}
+unsafe impl<T: ?Sized + Send> Send for OffsettingIterMut<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for OffsettingIterMut<'_, T> {}
 
-/// Implementation of vec-like methods for inner raw buffer
-impl<T: ?Sized> PolyPtrVec<T> {
+impl<'a, T: ?Sized> OffsettingIterMut<'a, T> {
     #[inline]
-    #[must_use]
-    pub fn raw_with_capacity(count: usize, buf_raw_capacity: usize) -> Self {
+    pub fn new<S: PolyStorage<T>>(poly_vec: &'a mut PolyVec<T, S>) -> OffsettingIterMut<'a, T> {
         Self {
-            view: Vec::with_capacity(count),
-            buf: Vec::with_capacity(buf_raw_capacity),
+            base: poly_vec.storage.buf_mut_ptr(),
+            iter: poly_vec.storage.views().iter(),
+            remaining: poly_vec.len(),
+            _marker: PhantomData,
         }
     }
+}
 
-    #[inline]
-    pub fn raw_capacity(&self) -> usize {
-        self.buf.capacity()
+impl<'a, T: ?Sized + 'a> OffsettingIterMut<'a, T> {
+    #[inline(always)]
+    fn offset_view(&self, view_offset: &OffsetDynView<T>) -> &'a mut T {
+        unsafe {
+            view_offset
+                .as_mut_view(self.base as *mut ())
+                .into_inner_mut()
+        }
     }
+}
+
+impl<'a, T: ?Sized + 'a> Iterator for OffsettingIterMut<'a, T> {
+    type Item = &'a mut T;
 
     #[inline]
-    pub fn raw_reserve(&mut self, additional: usize) {
-        self.buf.reserve(additional);
+    fn next(&mut self) -> Option<Self::Item> {
+        let view_offset = self.iter.find(|view| !view.is_dead())?;
+        self.remaining -= 1;
+        Some(self.offset_view(view_offset))
     }
 
     #[inline]
-    pub fn raw_reserve_exact(&mut self, additional: usize) {
-        self.buf.reserve_exact(additional)
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     }
 
     #[inline]
-    pub fn raw_try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
-        self.buf.try_reserve(additional)
+    fn count(self) -> usize {
+        self.remaining
     }
 
     #[inline]
-    pub fn raw_try_reserve_exact(&mut self, additional: usize) -> Result<(), TryReserveError> {
-        self.buf.try_reserve_exact(additional)
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        if self.remaining == self.iter.len() {
+            // No tombstones left, the views can be indexed directly
+            let view_offset = self.iter.nth(n);
+            self.remaining = self.iter.len();
+            return view_offset.map(|view_offset| self.offset_view(view_offset));
+        }
+        for _ in 0..n {
+            self.next()?;
+        }
+        self.next()
     }
 
     #[inline]
-    pub fn raw_shrink_to(&mut self, min_capacity: usize) {
-        self.buf.shrink_to(min_capacity)
+    fn last(mut self) -> Option<Self::Item> {
+        self.next_back()
     }
 }
 
-impl<T: ?Sized> PolyPtrVec<T> {
+impl<'a, T: ?Sized + 'a> DoubleEndedIterator for OffsettingIterMut<'a, T> {
     #[inline]
-    #[must_use]
-    pub fn len(&self) -> usize {
-        self.view.len()
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let view_offset = self.iter.rfind(|view| !view.is_dead())?;
+        self.remaining -= 1;
+        Some(self.offset_view(view_offset))
     }
 
     #[inline]
-    #[must_use]
-    pub fn is_empty(&self) -> bool {
-        self.view.len() == 0
+    fn nth_back(&mut self, n: usize) -> Option<Self::Item> {
+        if self.remaining == self.iter.len() {
+            let view_offset = self.iter.nth_back(n);
+            self.remaining = self.iter.len();
+            return view_offset.map(|view_offset| self.offset_view(view_offset));
+        }
+        for _ in 0..n {
+            self.next_back()?;
+        }
+        self.next_back()
     }
+}
 
-    #[inline]
-    #[must_use]
-    pub fn new() -> Self {
-        Self {
-            view: Vec::new(),
-            buf: Vec::new(),
-        }
+impl<'a, T: ?Sized + 'a> ExactSizeIterator for OffsettingIterMut<'a, T> {}
+
+impl<'a, T: ?Sized + 'a> FusedIterator for OffsettingIterMut<'a, T> {}
+
+/// Iterator over the elements of a [`PolyVec`] that are a `U`, created by [`PolyVec::iter_of`].
+pub struct IterOf<'a, T: ?Sized, U> {
+    base: *const u8,
+    iter: Iter<'a, OffsetDynView<T>>,
+    _marker: PhantomData<&'a U>,
+}
+
+unsafe impl<T: ?Sized, U: Sync> Send for IterOf<'_, T, U> {}
+unsafe impl<T: ?Sized, U: Sync> Sync for IterOf<'_, T, U> {}
+
+impl<'a, T: ?Sized, U: 'static> IterOf<'a, T, U> {
+    #[inline(always)]
+    fn value(&self, view: &OffsetDynView<T>) -> &'a U {
+        unsafe { &*(self.base.add(view.offset as usize) as *const U) }
     }
+}
+
+impl<'a, T: ?Sized, U: 'static> Iterator for IterOf<'a, T, U> {
+    type Item = &'a U;
 
     #[inline]
-    #[must_use]
-    pub fn with_capacity<U: Unsize<T>>(count: usize) -> Self {
-        Self {
-            view: Vec::with_capacity(count),
-            buf: Vec::with_capacity(count * aligned_size_of::<U>()),
-        }
+    fn next(&mut self) -> Option<Self::Item> {
+        let view = self.iter.find(|view| view.holds::<U>())?;
+        Some(self.value(view))
     }
 
     #[inline]
-    pub fn capacity<U: Unsize<T>>(&self) -> usize {
-        self.buf.capacity() / aligned_size_of::<U>()
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.iter.len()))
     }
+}
 
+impl<T: ?Sized, U: 'static> DoubleEndedIterator for IterOf<'_, T, U> {
     #[inline]
-    pub fn reserve<U: Unsize<T>>(&mut self, additional: usize) {
-        self.buf.reserve(additional * aligned_size_of::<U>());
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let view = self.iter.rfind(|view| view.holds::<U>())?;
+        Some(self.value(view))
     }
+}
 
-    #[inline]
-    pub fn reserve_exact<U: Unsize<T>>(&mut self, additional: usize) {
-        self.buf.reserve_exact(additional * aligned_size_of::<U>())
+impl<T: ?Sized, U: 'static> FusedIterator for IterOf<'_, T, U> {}
+
+impl<T: ?Sized, U> Clone for IterOf<'_, T, U> {
+    fn clone(&self) -> Self {
+        Self {
+            base: self.base,
+            iter: self.iter.clone(),
+            _marker: PhantomData,
+        }
     }
+}
 
-    #[inline]
-    pub fn try_reserve<U: Unsize<T>>(&mut self, additional: usize) -> Result<(), TryReserveError> {
-        self.buf.try_reserve(additional * aligned_size_of::<U>())
+/// Mutable iterator over the elements of a [`PolyVec`] that are a `U`,
+/// created by [`PolyVec::iter_of_mut`].
+pub struct IterOfMut<'a, T: ?Sized, U> {
+    base: *mut u8,
+    iter: Iter<'a, OffsetDynView<T>>,
+    _marker: PhantomData<&'a mut U>,
+}
+
+unsafe impl<T: ?Sized, U: Send> Send for IterOfMut<'_, T, U> {}
+unsafe impl<T: ?Sized, U: Sync> Sync for IterOfMut<'_, T, U> {}
+
+impl<'a, T: ?Sized, U: 'static> IterOfMut<'a, T, U> {
+    #[inline(always)]
+    fn value(&self, view: &OffsetDynView<T>) -> &'a mut U {
+        unsafe { &mut *(self.base.add(view.offset as usize) as *mut U) }
     }
+}
+
+impl<'a, T: ?Sized, U: 'static> Iterator for IterOfMut<'a, T, U> {
+    type Item = &'a mut U;
 
     #[inline]
-    pub fn try_reserve_exact<U: Unsize<T>>(
-        &mut self,
-        additional: usize,
-    ) -> Result<(), TryReserveError> {
-        self.buf
-            .try_reserve_exact(additional * aligned_size_of::<U>())
+    fn next(&mut self) -> Option<Self::Item> {
+        let view = self.iter.find(|view| view.holds::<U>())?;
+        Some(self.value(view))
     }
 
     #[inline]
-    pub fn shrink_to_fit(&mut self) {
-        self.buf.shrink_to_fit()
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.iter.len()))
     }
+}
 
+impl<T: ?Sized, U: 'static> DoubleEndedIterator for IterOfMut<'_, T, U> {
     #[inline]
-    pub fn shrink_to<U: Unsize<T>>(&mut self, min_capacity: usize) {
-        self.buf.shrink_to(min_capacity * aligned_size_of::<U>())
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let view = self.iter.rfind(|view| view.holds::<U>())?;
+        Some(self.value(view))
     }
+}
+
+impl<T: ?Sized, U: 'static> FusedIterator for IterOfMut<'_, T, U> {}
+
+/// Owning iterator of [`PolyVec`], every element is moved into its own [`Box`]
+/// from the global allocator.
+pub struct IntoIter<T: ?Sized, S: PolyStorage<T> = HeapStorage<T>> {
+    vec: PolyVec<T, S>,
+    front: usize,
+    back: usize,
+    /// Number of live elements between `front` and `back`
+    remaining: usize,
+}
+
+impl<T: ?Sized, S: PolyStorage<T>> Iterator for IntoIter<T, S> {
+    type Item = Box<T>;
 
     #[inline]
-    pub fn truncate(&mut self, len: usize) {
-        self.view.truncate(len);
-        match self.view.last() {
-            None => self.view.truncate(len),
-            Some(view) => self.buf.truncate(view.offset as usize),
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.front < self.back {
+            let index = self.front;
+            self.front += 1;
+            if !self.vec.storage.views()[index].is_dead() {
+                self.remaining -= 1;
+                return Some(unsafe { self.vec.box_element(index) });
+            }
         }
+        None
     }
 
     #[inline]
-    pub fn push<U: Unsize<T>>(&mut self, value: U) {
-        self.buf.reserve(aligned_size_of::<U>());
-        let view = self.push_value::<U>(value);
-        self.view.push(view);
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     }
+}
 
+impl<T: ?Sized, S: PolyStorage<T>> DoubleEndedIterator for IntoIter<T, S> {
     #[inline]
-    pub fn push_within_capacity<U: Unsize<T>>(&mut self, value: U) -> Result<(), U> {
-        if self.buf.len() == self.buf.capacity() {
-            return Err(value);
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.front < self.back {
+            self.back -= 1;
+            if !self.vec.storage.views()[self.back].is_dead() {
+                self.remaining -= 1;
+                return Some(unsafe { self.vec.box_element(self.back) });
+            }
         }
-        let view = self.push_value::<U>(value);
-        self.view.push(view);
-        Ok(())
+        None
     }
+}
 
-    #[inline]
-    pub fn insert<U: Unsize<T>>(&mut self, index: usize, element: U) {
-        self.buf.reserve(aligned_size_of::<U>());
-        let view = self.insert_element::<U>(index, element);
-        self.view.insert(index, view);
-        self.view
-            .iter_mut()
-            .skip(index + 1)
-            .for_each(|view| view.offset += aligned_size_of::<U>() as isize)
+impl<T: ?Sized, S: PolyStorage<T>> ExactSizeIterator for IntoIter<T, S> {}
+
+impl<T: ?Sized, S: PolyStorage<T>> FusedIterator for IntoIter<T, S> {}
+
+impl<T: ?Sized, S: PolyStorage<T>> IntoIter<T, S> {
+    fn drop_remaining(&mut self) {
+        unsafe {
+            let views = slice::from_raw_parts(
+                self.vec.storage.view_ptr().add(self.front),
+                self.back - self.front,
+            );
+            // Elements that were already moved out must not be dropped by the vec
+            self.vec.storage.set_view_len(0);
+            drop_elements(self.vec.storage.buf_mut_ptr(), views);
+        }
     }
+}
 
-    /// Remove is divided into 2 methods (remove and remove_ret)
-    /// Because elements have different types, they can have diffrent size. and
-    /// Returning diffrent size values from functions isn't stable for now
-    #[track_caller]
-    pub fn remove(&mut self, index: usize) {
-        let freed_space = self.remove_element(index);
-        self.view.remove(index);
-        self.view
-            .iter_mut()
-            .skip(index)
-            .for_each(|view| view.offset -= freed_space as isize)
+#[cfg(feature = "nightly")]
+unsafe impl<#[may_dangle] T: ?Sized, #[may_dangle] S: PolyStorage<T>> Drop for IntoIter<T, S> {
+    fn drop(&mut self) {
+        self.drop_remaining()
     }
+}
 
-    #[inline]
-    pub fn iter<'a>(&'a self) -> OffsettingIter<'a, T> {
-        OffsettingIter::<'a, T>::new(self)
+#[cfg(not(feature = "nightly"))]
+impl<T: ?Sized, S: PolyStorage<T>> Drop for IntoIter<T, S> {
+    fn drop(&mut self) {
+        self.drop_remaining()
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::fmt::Debug;
-    use std::mem::size_of;
+/// Draining iterator of [`PolyVec`], created by [`PolyVec::drain`].
+///
+/// The elements behind the drained range are moved down once, when the iterator is dropped.
+pub struct Drain<'a, T: ?Sized, S: PolyStorage<T> = HeapStorage<T>> {
+    vec: &'a mut PolyVec<T, S>,
+    /// Index of the first drained element
+    start: usize,
+    /// Index of the first element behind the drained range
+    end: usize,
+    /// Length of the view table before draining
+    len: usize,
+    /// Length of `buf` before draining
+    buf_len: usize,
+    front: usize,
+    back: usize,
+    /// Number of live elements between `front` and `back`
+    remaining: usize,
+}
 
-    #[allow(dead_code)]
-    #[derive(Debug)]
-    struct Example {
-        inner: f64,
+impl<T: ?Sized, S: PolyStorage<T>> Drain<'_, T, S> {
+    #[inline(always)]
+    unsafe fn view(&self, index: usize) -> &OffsetDynView<T> {
+        &*self.vec.storage.view_ptr().add(index)
     }
 
-    #[allow(dead_code)]
-    #[derive(Debug)]
-    struct Point {
-        x: f64,
-        y: f64,
-        z: f64,
+    /// Moves the elements behind the drained range into its place.
+    fn move_tail(&mut self) {
+        let tail_len = self.len - self.end;
+        unsafe {
+            if self.vec.fragmented() {
+                // Offsets don't follow the views anyway, so only the views move
+                let views = self.vec.storage.view_mut_ptr();
+                ptr::copy(views.add(self.end), views.add(self.start), tail_len);
+            } else if self.start == self.end {
+                self.vec.storage.set_buf_len(self.buf_len);
+            } else {
+                let write_end = self.vec.end_before(self.start);
+                self.vec.relayout(self.end, self.start, tail_len, write_end);
+            }
+            self.vec.storage.set_view_len(self.start + tail_len);
+        }
     }
+}
 
-    impl Example {
-        pub fn new(value: f64) -> Self {
-            Self { inner: value }
+impl<T: ?Sized, S: PolyStorage<T>> Iterator for Drain<'_, T, S> {
+    type Item = Box<T>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.front < self.back {
+            let index = self.front;
+            self.front += 1;
+            if unsafe { !self.view(index).is_dead() } {
+                self.remaining -= 1;
+                return Some(unsafe {
+                    box_value(self.vec.storage.buf_mut_ptr(), self.view(index))
+                });
+            }
         }
+        None
     }
 
-    impl Point {
-        pub fn new(x: f64, y: f64, z: f64) -> Self {
-            Self { x, y, z }
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: ?Sized, S: PolyStorage<T>> DoubleEndedIterator for Drain<'_, T, S> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.front < self.back {
+            self.back -= 1;
+            if unsafe { !self.view(self.back).is_dead() } {
+                self.remaining -= 1;
+                return Some(unsafe {
+                    box_value(self.vec.storage.buf_mut_ptr(), self.view(self.back))
+                });
+            }
         }
+        None
     }
+}
 
-    #[test]
-    fn push_without_resize() {
-        let mut vec = PolyPtrVec::<dyn Debug>::with_capacity::<Point>(4);
-        vec.push(Example::new(std::f64::consts::E));
-        vec.push(Example::new(std::f64::consts::PI));
-        vec.push(Point::new(0.5, 0.0, 1.7));
-        vec.push(Example::new(std::f64::consts::SQRT_2));
+impl<T: ?Sized, S: PolyStorage<T>> ExactSizeIterator for Drain<'_, T, S> {}
 
-        let mut last_addr: Option<usize> = None;
-        for (index, view) in vec.iter().enumerate() {
-            let pointer = view as *const dyn Debug;
-            let current_addr = pointer.addr();
-            let diff = if let Some(addr) = last_addr {
-                current_addr - addr
-            } else {
-                0
-            };
+impl<T: ?Sized, S: PolyStorage<T>> FusedIterator for Drain<'_, T, S> {}
 
-            let size_of_example = size_of::<Example>() + align_of::<Example>();
-            println!(
-                "{}: Value: {:?} | Addr: {} | Addr diff {} | Proper diff {}",
-                index, view, current_addr, diff, size_of_example
-            );
+impl<T: ?Sized, S: PolyStorage<T>> Drop for Drain<'_, T, S> {
+    fn drop(&mut self) {
+        /// Moves the tail even if one of the remaining destructors panics.
+        struct MoveTail<'r, 'a, T: ?Sized, S: PolyStorage<T>>(&'r mut Drain<'a, T, S>);
 
-            last_addr = Some(current_addr);
+        impl<T: ?Sized, S: PolyStorage<T>> Drop for MoveTail<'_, '_, T, S> {
+            fn drop(&mut self) {
+                self.0.move_tail()
+            }
         }
-    }
 
-    #[test]
-    fn push_with_resize() {
-        let mut vec = PolyPtrVec::<dyn Debug>::new();
-        vec.push(Example::new(std::f64::consts::E));
-        vec.push(Example::new(std::f64::consts::PI));
-        vec.push(Point::new(0.5, 0.0, 1.7));
-        vec.push(Example::new(std::f64::consts::SQRT_2));
-        vec.push(Point::new(0.25, 0.5, 1.5));
-        vec.push(Point::new(0.5, 0.5, 1.35));
+        let (front, back) = (self.front, self.back);
+        self.front = back;
+        let guard = MoveTail(self);
+        unsafe {
+            let views =
+                slice::from_raw_parts(guard.0.vec.storage.view_ptr().add(front), back - front);
+            drop_elements(guard.0.vec.storage.buf_mut_ptr(), views);
+        }
+    }
+}
 
-        let mut last_addr: Option<usize> = None;
-        for (index, view) in vec.iter().enumerate() {
-            let pointer = view as *const dyn Debug;
-            let current_addr = pointer.addr();
-            let diff = if let Some(addr) = last_addr {
-                current_addr - addr
-            } else {
-                0
-            };
+/// Vec of unsized values stored next to each other in the byte buffer of `S`.
+///
+/// Usually named through one of its storage aliases, [`PolyPtrVec`] or [`InlinePolyVec`].
+pub struct PolyVec<T: ?Sized, S: PolyStorage<T>> {
+    storage: S,
+    /// Whether removals leave holes, which are recorded in the free list of the storage
+    sparse: bool,
+    tombstones: Tombstones,
+    _marker: PhantomData<T>,
+}
 
-            let size_of_example = size_of::<Example>() + align_of::<Example>();
-            println!(
-                "{}: Value: {:?} | Addr: {} | Addr diff {} | Proper diff {}",
-                index, view, current_addr, diff, size_of_example
-            );
+/// Views of elements dropped by [`PolyVec::remove_deferred`] that are still in the vec.
+#[derive(Clone, Default)]
+struct Tombstones {
+    count: usize,
+    /// Bytes still occupied by the dropped elements
+    bytes: usize,
+}
 
-            last_addr = Some(current_addr);
+impl Tombstones {
+    /// Forgets the tombstones among `views`, which are leaving the vec.
+    #[inline]
+    fn forget<T: ?Sized>(&mut self, views: &[OffsetDynView<T>]) {
+        if self.count == 0 {
+            return;
+        }
+        for size in views.iter().filter_map(OffsetDynView::dead_size) {
+            self.count -= 1;
+            self.bytes -= size;
         }
     }
+}
 
-    #[test]
-    fn push_within_capacity() {
-        let mut vec = PolyPtrVec::<dyn Debug>::with_capacity::<Example>(3);
-        assert!(vec
-            .push_within_capacity(Example::new(std::f64::consts::E))
-            .is_ok());
-        assert!(vec
-            .push_within_capacity(Example::new(std::f64::consts::PI))
-            .is_ok());
-        assert!(vec
-            .push_within_capacity(Example::new(std::f64::consts::SQRT_2))
-            .is_ok());
-        assert!(vec
-            .push_within_capacity(Example::new(std::f64::consts::E))
-            .is_err());
-        assert!(vec
-            .push_within_capacity(Example::new(std::f64::consts::E))
-            .is_err());
+/// [`PolyVec`] on the heap, allocated in `A`.
+pub type PolyPtrVec<T, A = Global> = PolyVec<T, HeapStorage<T, A>>;
+
+/// [`PolyVec`] without heap allocations, holding up to `BYTES` bytes of elements and `N` elements.
+/// Elements aligned to more than [`INLINE_ALIGN`](crate::storage::INLINE_ALIGN) are rejected.
+pub type InlinePolyVec<T, const BYTES: usize, const N: usize = 16> =
+    PolyVec<T, InlineStorage<T, BYTES, N>>;
+
+/// [`PolyVec`] that keeps its elements inline like an [`InlinePolyVec`] while they fit,
+/// and moves them to the heap once they don't.
+pub type SmallPolyVec<T, const BYTES: usize, const N: usize = 16> =
+    PolyVec<T, SmallStorage<T, BYTES, N>>;
+
+impl<T: ?Sized, S: PolyStorage<T>> PolyVec<T, S> {
+    /// Evaluates the receiver of the `poly_*!` macros outside of their `unsafe` block.
+    #[doc(hidden)]
+    #[inline(always)]
+    pub fn __receiver(&mut self) -> &mut Self {
+        self
     }
 
-    #[test]
-    fn slice() {
-        let mut vec = PolyPtrVec::<[usize]>::with_capacity::<[usize; 25]>(1);
-        vec.push([3; 10]);
-        vec.push([10; 15]);
+    /// Writes `value` at the next offset aligned for `U`, `pointee` is the coerced pointer
+    /// to it, see [`coerce_value`]. Space for it has to be reserved by the caller,
+    /// see [`PolyVec::bytes_for`].
+    #[inline]
+    fn push_value<U>(&mut self, value: U, pointee: *const T) -> OffsetDynView<T> {
+        let offset = align_up(self.storage.buf_len(), align_of::<U>());
+        unsafe {
+            ptr::write(self.storage.buf_mut_ptr().add(offset) as *mut U, value);
+            self.storage.set_buf_len(offset + size_of::<U>());
+        }
+        OffsetDynView::<T>::from_value_ptr::<U>(offset as isize, pointee)
+    }
 
-        let result = vec.iter().flatten().sum::<usize>();
-        assert_eq!(180, result);
+    /// Appends `value` if there is room for it or the storage can grow,
+    /// otherwise hands it back.
+    #[inline]
+    #[track_caller]
+    unsafe fn try_push_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &mut self,
+        mut value: U,
+        coerce: F,
+    ) -> Result<(), (U, ReserveError)> {
+        let pointee = coerce_value(&mut value, coerce);
+        if self.sparse {
+            let hole = self
+                .storage
+                .try_reserve_views(1)
+                .and_then(|()| self.take_hole(Layout::new::<U>()));
+            let hole = match hole {
+                Ok(hole) => hole,
+                Err(err) => return Err((value, err)),
+            };
+            if let Some(offset) = hole {
+                unsafe { ptr::write(self.storage.buf_mut_ptr().add(offset) as *mut U, value) };
+                self.storage
+                    .push_view(OffsetDynView::<T>::from_value_ptr::<U>(
+                        offset as isize,
+                        pointee,
+                    ));
+                return Ok(());
+            }
+        }
+        let reserved = self.storage.try_reserve_views(1).and_then(|()| {
+            self.storage
+                .try_reserve_buf(self.bytes_for::<U>(1), align_of::<U>(), false)
+        });
+        if let Err(err) = reserved {
+            return Err((value, err));
+        }
+        let view = self.push_value::<U>(value, pointee);
+        self.storage.push_view(view);
+        Ok(())
+    }
+
+    /// Inserts `element` at `index` if there is room for it or the storage can grow,
+    /// otherwise hands it back.
+    #[inline]
+    #[track_caller]
+    unsafe fn try_insert_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &mut self,
+        index: usize,
+        mut element: U,
+        coerce: F,
+    ) -> Result<(), (U, ReserveError)> {
+        let pointee = coerce_value(&mut element, coerce);
+        let (offset, shift) = match self.try_make_room(index, Layout::new::<U>()) {
+            Ok(room) => room,
+            Err(err) => return Err((element, err)),
+        };
+        unsafe { ptr::write(self.storage.buf_mut_ptr().add(offset) as *mut U, element) };
+        self.insert_view(
+            index,
+            OffsetDynView::<T>::from_value_ptr::<U>(offset as isize, pointee),
+            shift,
+        );
+        Ok(())
+    }
+
+    /// Opens a gap for a value of `layout` in front of the element at `index`, or at the end of
+    /// `buf` when `index == len`, and reserves a view for it. Returns the offset of the gap
+    /// and by how many bytes the tail moved.
+    ///
+    /// In sparse mode nothing moves, the value goes into a hole or behind the last element.
+    #[track_caller]
+    fn try_make_room(
+        &mut self,
+        index: usize,
+        layout: Layout,
+    ) -> Result<(usize, usize), ReserveError> {
+        let view_len = self.storage.view_len();
+        #[cold]
+        #[inline(never)]
+        #[track_caller]
+        fn assert_failed(index: usize, len: usize) -> ! {
+            panic!("insertion index (is {index}) should be <= len (is {len})");
+        }
+
+        let start = match self.storage.views().get(index) {
+            Some(view) if !self.sparse => view.offset as usize,
+            _ => {
+                if index > view_len {
+                    assert_failed(index, view_len);
+                }
+                self.storage.try_reserve_views(1)?;
+                let hole = if self.sparse {
+                    self.take_hole(layout)?
+                } else {
+                    None
+                };
+                let offset = match hole {
+                    Some(offset) => offset,
+                    None => {
+                        let offset = align_up(self.storage.buf_len(), layout.align());
+                        let end = offset + layout.size();
+                        self.storage.try_reserve_buf(
+                            end - self.storage.buf_len(),
+                            layout.align(),
+                            false,
+                        )?;
+                        unsafe { self.storage.set_buf_len(end) };
+                        offset
+                    }
+                };
+                // Only sparse vecs get here with elements behind `index`
+                if index < view_len {
+                    self.mark_fragmented();
+                }
+                return Ok((offset, 0));
+            }
+        };
+
+        // The tail moves by a multiple of the largest alignment in the buffer,
+        // so every element behind the new one stays aligned.
+        let offset = align_up(start, layout.align());
+        let align = self.storage.buf_align().max(layout.align());
+        let shift = align_up(offset + layout.size() - start, align);
+        self.storage.try_reserve_views(1)?;
+        self.storage.try_reserve_buf(shift, layout.align(), false)?;
+
+        unsafe {
+            let src = self.storage.buf_mut_ptr().add(start);
+            ptr::copy(src, src.add(shift), self.storage.buf_len() - start);
+            self.storage.set_buf_len(self.storage.buf_len() + shift);
+        }
+        Ok((offset, shift))
+    }
+
+    /// Moves the value behind `src` into the vec at `index`, `view` is its former view.
+    /// The memory of `src` is left as is, so the caller has to make sure the value isn't
+    /// dropped a second time.
+    unsafe fn insert_raw(&mut self, index: usize, src: *const T, mut view: OffsetDynView<T>) {
+        let layout = Layout::for_value(&*src);
+        let (offset, shift) = self
+            .try_make_room(index, layout)
+            .unwrap_or_else(|err| err.handle());
+        let dst = self.storage.buf_mut_ptr().add(offset);
+        ptr::copy_nonoverlapping(src as *const u8, dst, layout.size());
+        view.offset = offset as isize;
+        self.insert_view(index, view, shift);
+    }
+
+    /// Inserts `view` into the reserved slot at `index` and moves the views behind it by `shift`.
+    #[inline]
+    fn insert_view(&mut self, index: usize, view: OffsetDynView<T>, shift: usize) {
+        self.storage.insert_view(index, view);
+        self.storage
+            .views_mut()
+            .iter_mut()
+            .skip(index + 1)
+            .for_each(|view| view.offset += shift as isize)
+    }
+
+    /// Removes the element at `index` without dropping it, and moves the elements behind it down.
+    #[track_caller]
+    fn close_gap(&mut self, index: usize) {
+        let len = self.storage.view_len();
+        check_removal_index(index, len);
+        let write_end = self.end_before(index);
+        unsafe {
+            self.relayout(index + 1, index, len - index - 1, write_end);
+            self.storage.set_view_len(len - 1);
+        }
+    }
+
+    /// End of the bytes of the element in front of `index`, or 0 for the first one.
+    fn end_before(&self, index: usize) -> usize {
+        match index.checked_sub(1) {
+            Some(index) => self.bytes_of(&self.storage.views()[index]).end,
+            None => 0,
+        }
+    }
+
+    /// Bytes of `buf` taken by the element of `view`, which has to belong to this vec
+    /// and not be dropped yet unless it is a tombstone.
+    #[inline]
+    fn bytes_of(&self, view: &OffsetDynView<T>) -> Range<usize> {
+        let size = match view.dead_size() {
+            Some(size) => size,
+            None => unsafe {
+                size_of_val(
+                    view.as_view(self.storage.buf_ptr() as *const ())
+                        .into_inner(),
+                )
+            },
+        };
+        view.offset as usize..view.offset as usize + size
+    }
+
+    /// Moves the `count` views starting at index `src` down to index `dst` and lays their
+    /// elements out again behind `write_end`, each at the next offset aligned for it.
+    /// `buf` then ends behind the last of them. Tombstones give up their bytes.
+    ///
+    /// Moving every element by its own alignment, instead of the tail as one block by a
+    /// multiple of the buffer alignment, frees all of the gap even after an element with
+    /// a large alignment was stored.
+    ///
+    /// # Safety
+    /// `dst <= src`, the views must be initialized, even if they lie behind the view length,
+    /// and be in offset order with their elements starting at or behind `write_end`.
+    unsafe fn relayout(&mut self, src: usize, dst: usize, count: usize, mut write_end: usize) {
+        let base = self.storage.buf_mut_ptr();
+        let views = self.storage.view_mut_ptr();
+        for index in 0..count {
+            let mut view = views.add(src + index).read();
+            if let Some(size) = view.dead_size() {
+                self.tombstones.bytes -= size;
+                view.kill(0);
+                view.offset = write_end as isize;
+            } else {
+                let value = view.as_mut_view(base as *mut ()).inner().as_ptr();
+                let layout = Layout::for_value(&*value);
+                let offset = align_up(write_end, layout.align());
+                if offset != view.offset as usize {
+                    ptr::copy(value as *const u8, base.add(offset), layout.size());
+                    view.offset = offset as isize;
+                }
+                write_end = offset + layout.size();
+            }
+            views.add(dst + index).write(view);
+        }
+        self.storage.set_buf_len(write_end);
+    }
+
+    /// Copies the element at `index` into a new allocation. The element is left in the vec,
+    /// so the caller has to make sure it isn't dropped a second time.
+    #[inline]
+    unsafe fn box_element(&mut self, index: usize) -> Box<T> {
+        box_value(
+            self.storage.buf_mut_ptr(),
+            self.storage.views().get_unchecked(index),
+        )
+    }
+
+    #[inline]
+    fn element_ptr(&mut self, index: usize) -> *mut T {
+        unsafe { self.get_unchecked_mut(index) as *mut T }
+    }
+
+    /// Number of bytes `count` values of `U` need when pushed after the current end of `buf`.
+    #[inline]
+    fn bytes_for<U>(&self, count: usize) -> usize {
+        let padding = align_up(self.storage.buf_len(), align_of::<U>()) - self.storage.buf_len();
+        size_of::<U>().saturating_mul(count).saturating_add(padding)
+    }
+
+    /// Whether offsets may be out of view order or have holes between them,
+    /// which only happens in sparse mode.
+    #[inline]
+    fn fragmented(&self) -> bool {
+        self.storage.free_list().is_some_and(|free| free.fragmented)
+    }
+
+    /// Records that offsets may no longer follow the views, only called in sparse mode.
+    #[inline]
+    fn mark_fragmented(&mut self) {
+        if let Some((free, _)) = self.storage.free_list_mut() {
+            free.fragmented = true;
+        }
+    }
+
+    /// Takes a hole with room for `layout` from the free list, after aligning
+    /// the base of `buf` for it.
+    #[inline]
+    fn take_hole(&mut self, layout: Layout) -> Result<Option<usize>, ReserveError> {
+        self.storage.try_reserve_buf(0, layout.align(), false)?;
+        Ok(self
+            .storage
+            .free_list_mut()
+            .and_then(|(free, alloc)| free.take(layout, alloc)))
+    }
+
+    /// Restores sorted offsets without holes after [`PolyVec::set_sparse`] is turned off or
+    /// before appending the whole vec. Tombstones keep their index, but their bytes are reclaimed.
+    #[inline]
+    fn make_dense(&mut self) {
+        if self.fragmented() {
+            self.squeeze(true)
+        }
+    }
+
+    /// Moves the live elements next to each other in view order. Tombstones are
+    /// dropped from the view table unless `keep_tombstones` is set, in which case
+    /// they stay as empty elements.
+    ///
+    /// A dense vec is squeezed in place in a single pass. The elements of a fragmented
+    /// sparse vec may be out of order, so its bytes are copied once into a temporary
+    /// buffer from the allocator of the storage, and from there back into place.
+    /// Their new order can need more padding, so `buf` is grown for it first.
+    fn squeeze(&mut self, keep_tombstones: bool) {
+        let fragmented = self.fragmented();
+        if fragmented {
+            let (dense_len, buf_len) = (self.dense_len(), self.storage.buf_len());
+            if dense_len > buf_len {
+                self.storage
+                    .reserve_buf(dense_len - buf_len, self.storage.buf_align());
+            }
+        }
+        let buf_len = self.storage.buf_len();
+        let align = self.storage.buf_align();
+        let base = self.storage.buf_mut_ptr();
+        let mut old = None;
+        if fragmented {
+            if let Some((free, alloc)) = self.storage.free_list_mut() {
+                let mut copy = RawPolyBuf::with_capacity(buf_len, align, alloc);
+                unsafe { ptr::copy_nonoverlapping(base, copy.as_mut_ptr(), buf_len) };
+                free.clear();
+                old = Some(copy);
+            }
+        }
+        let src_base = old.as_mut().map_or(base, RawPolyBuf::as_mut_ptr);
+
+        let len = self.storage.view_len();
+        let views = self.storage.view_mut_ptr();
+        let mut end = 0;
+        let mut kept = 0;
+        unsafe {
+            for index in 0..len {
+                let mut view = views.add(index).read();
+                if view.is_dead() {
+                    if !keep_tombstones {
+                        continue;
+                    }
+                    view.offset = end as isize;
+                    view.kill(0);
+                } else {
+                    let src = view.as_mut_view(src_base as *mut ()).inner().as_ptr();
+                    let layout = Layout::for_value(&*src);
+                    let offset = align_up(end, layout.align());
+                    if base.add(offset) != src as *mut u8 {
+                        ptr::copy(src as *const u8, base.add(offset), layout.size());
+                    }
+                    view.offset = offset as isize;
+                    end = offset + layout.size();
+                }
+                views.add(kept).write(view);
+                kept += 1;
+            }
+            self.storage.set_view_len(kept);
+            self.storage.set_buf_len(end);
+        }
+        if keep_tombstones {
+            self.tombstones.bytes = 0;
+        } else {
+            self.tombstones = Tombstones::default();
+        }
+        if let Some(mut old) = old {
+            if let Some((_, alloc)) = self.storage.free_list_mut() {
+                old.free(alloc);
+            }
+        }
+    }
+
+    /// Length of `buf` once the live elements are laid out next to each other in view order.
+    fn dense_len(&self) -> usize {
+        let base = self.storage.buf_ptr();
+        let live = self.storage.views().iter().filter(|view| !view.is_dead());
+        live.fold(0, |end, view| {
+            let layout = Layout::for_value(unsafe { view.as_view(base as *const ()).into_inner() });
+            align_up(end, layout.align()) + layout.size()
+        })
+    }
+
+    /// Removes the tombstones at the end of the vec.
+    fn discard_dead_tail(&mut self) {
+        while let Some(view) = self.storage.views().last() {
+            if !view.is_dead() {
+                break;
+            }
+            self.remove(self.storage.view_len() - 1);
+        }
+    }
+
+    /// Removes the view at `index` in sparse mode and records the `size` bytes
+    /// of its element as a hole.
+    ///
+    /// # Safety
+    /// The element must have been moved out or be dropped by the caller.
+    unsafe fn leave_hole(&mut self, index: usize, size: usize) {
+        // Removing the last element of a dense vec keeps it dense
+        if index + 1 != self.storage.view_len() {
+            self.mark_fragmented();
+        }
+        let start = self.storage.remove_view(index).offset as usize;
+        self.release(start..start + size);
+    }
+
+    /// Gives back the bytes of an element removed in sparse mode: they are cut off `buf`
+    /// if nothing lies behind them, and recorded as a hole otherwise.
+    fn release(&mut self, bytes: Range<usize>) {
+        if bytes.end >= self.storage.buf_len() {
+            unsafe { self.storage.set_buf_len(bytes.start) };
+        } else if let Some((free, alloc)) = self.storage.free_list_mut() {
+            free.insert(bytes, alloc);
+            free.fragmented = true;
+        }
+    }
+}
+
+/// Closes the gap left by a removed element, even if its destructor panics.
+struct RemoveGuard<'a, T: ?Sized, S: PolyStorage<T>> {
+    vec: &'a mut PolyVec<T, S>,
+    index: usize,
+}
+
+impl<T: ?Sized, S: PolyStorage<T>> Drop for RemoveGuard<'_, T, S> {
+    fn drop(&mut self) {
+        self.vec.close_gap(self.index)
+    }
+}
+
+/// Restores a consistent vec after [`PolyVec::retain_mut`], also when unwinding.
+struct RetainGuard<'a, T: ?Sized, S: PolyStorage<T>> {
+    vec: &'a mut PolyVec<T, S>,
+    processed: usize,
+    deleted: usize,
+    original_len: usize,
+    /// End of the last kept element in `buf`
+    write_end: usize,
+    /// Whether the elements stay where they are and only the views move down
+    fragmented: bool,
+}
+
+impl<T: ?Sized, S: PolyStorage<T>> Drop for RetainGuard<'_, T, S> {
+    fn drop(&mut self) {
+        let tail_len = self.original_len - self.processed;
+        unsafe {
+            if self.fragmented {
+                let views = self.vec.storage.view_mut_ptr();
+                ptr::copy(
+                    views.add(self.processed),
+                    views.add(self.processed - self.deleted),
+                    tail_len,
+                );
+            } else {
+                self.vec.relayout(
+                    self.processed,
+                    self.processed - self.deleted,
+                    tail_len,
+                    self.write_end,
+                );
+            }
+            self.vec
+                .storage
+                .set_view_len(self.original_len - self.deleted);
+        }
+    }
+}
+
+/// Runs `coerce` on `value` and returns the resulting pointer, whose metadata describes `value` as a `T`.
+///
+/// # Safety
+/// `coerce` has to return `value` itself, which stable Rust can't express as a bound.
+/// Pointers to anything else at another address or with another size are still caught.
+#[inline]
+#[track_caller]
+pub(crate) unsafe fn coerce_value<U, T: ?Sized, F: FnOnce(&mut U) -> &mut T>(
+    value: &mut U,
+    coerce: F,
+) -> *const T {
+    let addr = value as *mut U as *const u8;
+    let pointee = coerce(value);
+    assert!(
+        ptr::eq(pointee as *const T as *const u8, addr)
+            && size_of_val(pointee) == size_of::<U>()
+            && align_of_val(pointee) == align_of::<U>(),
+        "`coerce` has to return the value it was given"
+    );
+    pointee as *const T
+}
+
+/// Copies the element described by `view` into a new allocation.
+pub(crate) unsafe fn box_value<T: ?Sized>(base: *mut u8, view: &OffsetDynView<T>) -> Box<T> {
+    let src = view.as_mut_view(base as *mut ()).inner().as_ptr();
+    let layout = Layout::for_value(&*src);
+    let dst = if layout.size() == 0 {
+        ptr::without_provenance_mut::<u8>(layout.align())
+    } else {
+        let dst = heap::alloc(layout);
+        if dst.is_null() {
+            heap::handle_alloc_error(layout);
+        }
+        dst
+    };
+    ptr::copy_nonoverlapping(src as *const u8, dst, layout.size());
+    Box::from_raw(view.ptr_at(dst as *mut ()))
+}
+
+/// Drops every live element described by `views`. If one of the destructors panics
+/// the remaining elements are still dropped while unwinding.
+unsafe fn drop_elements<T: ?Sized>(base: *mut u8, views: &[OffsetDynView<T>]) {
+    struct DropGuard<'a, T: ?Sized> {
+        base: *mut u8,
+        views: Iter<'a, OffsetDynView<T>>,
+    }
+
+    impl<T: ?Sized> Drop for DropGuard<'_, T> {
+        fn drop(&mut self) {
+            for view in self.views.by_ref().filter(|view| !view.is_dead()) {
+                unsafe {
+                    ptr::drop_in_place(view.as_mut_view(self.base as *mut ()).inner().as_ptr())
+                }
+            }
+        }
+    }
+
+    let mut guard = DropGuard {
+        base,
+        views: views.iter(),
+    };
+    for view in guard.views.by_ref().filter(|view| !view.is_dead()) {
+        ptr::drop_in_place(view.as_mut_view(base as *mut ()).inner().as_ptr());
+    }
+}
+
+impl<T: ?Sized> Default for PolyPtrVec<T> {
+    fn default() -> Self {
+        PolyPtrVec::<T>::new()
+    }
+}
+
+impl<T: ?Sized> PolyPtrVec<T> {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self::new_in(Global)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn with_capacity<U>(count: usize) -> Self {
+        Self::with_capacity_in::<U>(count, Global)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn raw_with_capacity(count: usize, buf_raw_capacity: usize) -> Self {
+        Self::raw_with_capacity_in(count, buf_raw_capacity, Global)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> PolyPtrVec<T, A> {
+    /// Creates an empty vec that allocates in `alloc` once elements are added.
+    #[inline]
+    #[must_use]
+    pub const fn new_in(alloc: A) -> Self {
+        Self::from_storage(HeapStorage::new_in(alloc))
+    }
+
+    /// Creates a vec with room for `count` values of `U`, allocated in `alloc`.
+    #[inline]
+    #[must_use]
+    pub fn with_capacity_in<U>(count: usize, alloc: A) -> Self {
+        Self::from_storage(HeapStorage::with_capacity_in(
+            count,
+            count * size_of::<U>(),
+            align_of::<U>(),
+            alloc,
+        ))
+    }
+
+    /// Creates a vec with room for `count` views and `buf_raw_capacity` bytes,
+    /// both allocated in `alloc`.
+    #[inline]
+    #[must_use]
+    pub fn raw_with_capacity_in(count: usize, buf_raw_capacity: usize, alloc: A) -> Self {
+        Self::from_storage(HeapStorage::with_capacity_in(
+            count,
+            buf_raw_capacity,
+            1,
+            alloc,
+        ))
+    }
+
+    #[inline]
+    pub fn allocator(&self) -> &A {
+        self.storage.allocator()
+    }
+
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub fn push<U: Unsize<T>>(&mut self, value: U) {
+        // The closure returns its argument
+        unsafe { self.push_coerce(value, |value| value) }
+    }
+
+    /// Appends `value`, which `coerce` turns into a `T`. This is how elements are added
+    /// on stable Rust, usually through [`poly_push!`](crate::poly_push).
+    ///
+    /// # Safety
+    /// `coerce` has to return its argument, only coerced to `T`. Returning anything else,
+    /// even a field at the same address, records the wrong metadata and type for `value`.
+    ///
+    /// Projections need an `unsafe` block, the macros only ever pass `|value| value`:
+    ///
+    /// ```compile_fail,E0133
+    /// # use std::fmt::Debug;
+    /// # use trait_vec::trait_vec::PolyPtrVec;
+    /// #[derive(Debug)]
+    /// struct Inner(u8);
+    /// #[derive(Debug)]
+    /// struct Outer(Inner);
+    ///
+    /// let mut vec = PolyPtrVec::<dyn Debug>::new();
+    /// vec.push_coerce(Outer(Inner(1)), |outer| &mut outer.0 as &mut dyn Debug);
+    /// ```
+    #[inline]
+    #[track_caller]
+    pub unsafe fn push_coerce<U, F: FnOnce(&mut U) -> &mut T>(&mut self, value: U, coerce: F) {
+        if let Err((_, err)) = self.try_push_coerce(value, coerce) {
+            err.handle()
+        }
+    }
+
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub fn insert<U: Unsize<T>>(&mut self, index: usize, element: U) {
+        // The closure returns its argument
+        unsafe { self.insert_coerce(index, element, |element| element) }
+    }
+
+    /// Inserts `element` at `index`.
+    ///
+    /// # Safety
+    /// See [`PolyPtrVec::push_coerce`].
+    #[inline]
+    #[track_caller]
+    pub unsafe fn insert_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &mut self,
+        index: usize,
+        element: U,
+        coerce: F,
+    ) {
+        if let Err((_, err)) = self.try_insert_coerce(index, element, coerce) {
+            err.handle()
+        }
+    }
+
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub fn extend_from_vec<U: Unsize<T>>(&mut self, values: Vec<U>) {
+        // The closure returns its argument
+        unsafe { self.extend_from_vec_coerce(values, |value| value) }
+    }
+
+    /// Moves all `values` to the end of the vec with a single reservation and a single copy.
+    ///
+    /// `coerce` only runs on the first value, the others share the metadata it produces.
+    ///
+    /// # Safety
+    /// `coerce` has to return its argument, only coerced to `T`.
+    #[track_caller]
+    pub unsafe fn extend_from_vec_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &mut self,
+        mut values: Vec<U>,
+        coerce: F,
+    ) {
+        let Some(first) = values.first_mut() else {
+            return;
+        };
+        let view = OffsetDynView::<T>::from_value_ptr::<U>(0, coerce_value(first, coerce));
+        let count = values.len();
+        self.storage.reserve_views(count);
+        if let Err(err) =
+            self.storage
+                .try_reserve_buf(self.bytes_for::<U>(count), align_of::<U>(), true)
+        {
+            err.handle()
+        }
+
+        // Sizes are multiples of the alignment, so the values stay aligned one after another
+        let start = align_up(self.storage.buf_len(), align_of::<U>());
+        let len = self.storage.view_len();
+        unsafe {
+            ptr::copy_nonoverlapping(
+                values.as_ptr() as *const u8,
+                self.storage.buf_mut_ptr().add(start),
+                count * size_of::<U>(),
+            );
+            // The values belong to the vec now, `values` only frees its allocation
+            values.set_len(0);
+            self.storage.set_buf_len(start + count * size_of::<U>());
+            let views = self.storage.view_mut_ptr().add(len);
+            for index in 0..count {
+                let mut view = view.clone();
+                view.offset = (start + index * size_of::<U>()) as isize;
+                views.add(index).write(view);
+            }
+            self.storage.set_view_len(len + count);
+        }
+    }
+
+    /// Appends every value of `iter`.
+    ///
+    /// # Safety
+    /// `coerce` has to return its argument, only coerced to `T`.
+    #[track_caller]
+    pub unsafe fn extend_coerce<U, I: IntoIterator<Item = U>, F: FnMut(&mut U) -> &mut T>(
+        &mut self,
+        iter: I,
+        mut coerce: F,
+    ) {
+        let iter = iter.into_iter();
+        let (additional, _) = iter.size_hint();
+        self.reserve::<U>(additional);
+        self.storage.reserve_views(additional);
+        iter.for_each(|value| self.push_coerce(value, &mut coerce));
+    }
+
+    /// Moves all elements of `other` to the end of the vec, leaving `other` empty.
+    ///
+    /// The bytes of `other` are copied in one block behind a gap that keeps them aligned,
+    /// and its views are rebased onto it.
+    #[track_caller]
+    pub fn append(&mut self, other: &mut Self) {
+        other.make_dense();
+        let count = other.storage.view_len();
+        if count == 0 {
+            return;
+        }
+        let align = other.storage.buf_align();
+        let bytes = other.storage.buf_len();
+        let start = align_up(self.storage.buf_len(), align);
+        self.storage.reserve_views(count);
+        self.storage
+            .reserve_buf(start - self.storage.buf_len() + bytes, align);
+
+        let len = self.storage.view_len();
+        unsafe {
+            ptr::copy_nonoverlapping(
+                other.storage.buf_ptr(),
+                self.storage.buf_mut_ptr().add(start),
+                bytes,
+            );
+            self.storage.set_buf_len(start + bytes);
+            let views = self.storage.view_mut_ptr().add(len);
+            for (index, view) in other.storage.views().iter().enumerate() {
+                let mut view = view.clone();
+                view.offset += start as isize;
+                views.add(index).write(view);
+            }
+            self.storage.set_view_len(len + count);
+            // The elements belong to `self` now
+            other.storage.set_view_len(0);
+            other.storage.set_buf_len(0);
+        }
+        let tombstones = mem::take(&mut other.tombstones);
+        self.tombstones.count += tombstones.count;
+        self.tombstones.bytes += tombstones.bytes;
+    }
+
+    /// Moves the element at `index` to the end of `dst`, see [`PolyPtrVec::transfer_to_index`].
+    #[inline]
+    #[track_caller]
+    pub fn transfer(&mut self, index: usize, dst: &mut Self) {
+        self.transfer_to_index(index, dst, dst.len_with_tombstones())
+    }
+
+    /// Moves the element at `index` into `dst` at `dst_index`, together with its metadata
+    /// and recorded type. Its bytes are copied to an offset of `dst` aligned for it.
+    ///
+    /// # Panics
+    /// Panics if `index` is out of bounds or a tombstone, or if `dst_index > dst.len_with_tombstones()`.
+    #[track_caller]
+    pub fn transfer_to_index(&mut self, index: usize, dst: &mut Self, dst_index: usize) {
+        check_removal_index(index, self.storage.view_len());
+        self.check_live(index);
+        let view = self.storage.views()[index].clone();
+        let src = self.element_ptr(index);
+        let size = unsafe {
+            dst.insert_raw(dst_index, src, view);
+            size_of_val(&*src)
+        };
+        // The element belongs to `dst` now
+        if self.sparse {
+            unsafe { self.leave_hole(index, size) };
+        } else {
+            self.close_gap(index);
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator + Clone> PolyPtrVec<T, A> {
+    /// Moves the elements from `at` on into a new vec in the same allocator, sized
+    /// for exactly their bytes. Each element is copied to the next offset aligned
+    /// for it, starting at 0, and tombstones keep their index.
+    ///
+    /// # Panics
+    /// Panics if `at > len`.
+    #[track_caller]
+    #[must_use = "use `.truncate()` if you don't need the other half"]
+    pub fn split_off(&mut self, at: usize) -> Self {
+        #[cold]
+        #[inline(never)]
+        #[track_caller]
+        fn assert_failed(at: usize, len: usize) -> ! {
+            panic!("`at` split index (is {at}) should be <= len (is {len})");
+        }
+
+        let len = self.storage.view_len();
+        if at > len {
+            assert_failed(at, len);
+        }
+        let base = self.storage.buf_ptr();
+        let tail_views =
+            unsafe { slice::from_raw_parts(self.storage.view_ptr().add(at), len - at) };
+        let layout_of = |view: &OffsetDynView<T>| {
+            view.dead_size()
+                .is_none()
+                .then(|| Layout::for_value(unsafe { view.as_view(base as *const ()).into_inner() }))
+        };
+
+        // Lay the tail out again from offset 0, each element by its own alignment, so no
+        // leading padding is carried over. Tombstones don't keep their bytes in the new vec.
+        // The size is worked out up front so nothing can panic while elements are copied.
+        let (mut bytes, mut align) = (0, 1);
+        for layout in tail_views.iter().filter_map(layout_of) {
+            bytes = align_up(bytes, layout.align()) + layout.size();
+            align = align.max(layout.align());
+        }
+        let storage =
+            HeapStorage::with_capacity_in(len - at, bytes, align, self.storage.allocator().clone());
+        let mut tail = Self::from_storage(storage);
+        for view in tail_views {
+            let mut view = view.clone();
+            let write_end = tail.storage.buf_len();
+            if let Some(layout) = layout_of(&view) {
+                let offset = align_up(write_end, layout.align());
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        base.add(view.offset as usize),
+                        tail.storage.buf_mut_ptr().add(offset),
+                        layout.size(),
+                    );
+                    tail.storage.set_buf_len(offset + layout.size());
+                }
+                view.offset = offset as isize;
+            } else {
+                tail.tombstones.count += 1;
+                view.kill(0);
+                view.offset = write_end as isize;
+            }
+            tail.storage.push_view(view);
+        }
+        self.tombstones.forget(tail_views);
+        if self.fragmented() {
+            for view in tail_views {
+                self.release(self.bytes_of(view));
+            }
+        } else {
+            let head_end = self.end_before(at);
+            unsafe { self.storage.set_buf_len(head_end) };
+        }
+        unsafe { self.storage.set_view_len(at) };
+        tail
+    }
+}
+
+/// Reserves room for the lower bound of the size hint once, then pushes every value.
+///
+/// Only available with the `nightly` feature, which `U: Unsize<T>` needs. On stable Rust
+/// use [`poly_extend!`](crate::poly_extend) or [`PolyPtrVec::extend_coerce`] instead.
+#[cfg(feature = "nightly")]
+impl<T: ?Sized, U: Unsize<T>, A: Allocator> Extend<U> for PolyPtrVec<T, A> {
+    fn extend<I: IntoIterator<Item = U>>(&mut self, iter: I) {
+        // The closure returns its argument
+        unsafe { self.extend_coerce(iter, |value| value) }
+    }
+}
+
+/// Only available with the `nightly` feature. On stable Rust extend an empty vec
+/// with [`poly_extend!`](crate::poly_extend) instead.
+#[cfg(feature = "nightly")]
+impl<T: ?Sized, U: Unsize<T>> FromIterator<U> for PolyPtrVec<T> {
+    fn from_iter<I: IntoIterator<Item = U>>(iter: I) -> Self {
+        let mut vec = Self::new();
+        vec.extend(iter);
+        vec
+    }
+}
+
+impl<T: ?Sized, const BYTES: usize, const N: usize> Default for InlinePolyVec<T, BYTES, N> {
+    fn default() -> Self {
+        InlinePolyVec::<T, BYTES, N>::new()
+    }
+}
+
+impl<T: ?Sized, const BYTES: usize, const N: usize> InlinePolyVec<T, BYTES, N> {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self::from_storage(InlineStorage::new())
+    }
+
+    /// Appends `value`, or hands it back if it doesn't fit into the remaining bytes and views.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub fn push<U: Unsize<T>>(&mut self, value: U) -> Result<(), U> {
+        // The closure returns its argument
+        unsafe { self.push_coerce(value, |value| value) }
+    }
+
+    /// Appends `value`, or hands it back if it doesn't fit.
+    ///
+    /// # Safety
+    /// See [`PolyPtrVec::push_coerce`].
+    #[inline]
+    #[track_caller]
+    pub unsafe fn push_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &mut self,
+        value: U,
+        coerce: F,
+    ) -> Result<(), U> {
+        self.try_push_coerce(value, coerce)
+            .map_err(|(value, _)| value)
+    }
+
+    /// Inserts `element` at `index`, or hands it back if it doesn't fit.
+    ///
+    /// # Panics
+    /// Panics if `index > len`.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub fn insert<U: Unsize<T>>(&mut self, index: usize, element: U) -> Result<(), U> {
+        // The closure returns its argument
+        unsafe { self.insert_coerce(index, element, |element| element) }
+    }
+
+    /// Inserts `element` at `index`, or hands it back if it doesn't fit.
+    ///
+    /// # Safety
+    /// See [`PolyPtrVec::push_coerce`].
+    #[inline]
+    #[track_caller]
+    pub unsafe fn insert_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &mut self,
+        index: usize,
+        element: U,
+        coerce: F,
+    ) -> Result<(), U> {
+        self.try_insert_coerce(index, element, coerce)
+            .map_err(|(element, _)| element)
+    }
+}
+
+impl<T: ?Sized, const BYTES: usize, const N: usize> Default for SmallPolyVec<T, BYTES, N> {
+    fn default() -> Self {
+        SmallPolyVec::<T, BYTES, N>::new()
+    }
+}
+
+impl<T: ?Sized, const BYTES: usize, const N: usize> SmallPolyVec<T, BYTES, N> {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self::from_storage(SmallStorage::new())
+    }
+
+    /// Creates a vec with room for `count` values of `U`, on the heap if they don't fit inline.
+    #[inline]
+    #[must_use]
+    pub fn with_capacity<U>(count: usize) -> Self {
+        let mut vec = Self::new();
+        vec.reserve_exact::<U>(count);
+        vec.storage.reserve_views(count);
+        vec
+    }
+
+    /// Creates a vec with room for `count` views and `buf_raw_capacity` bytes,
+    /// on the heap if they don't fit inline.
+    #[inline]
+    #[must_use]
+    pub fn raw_with_capacity(count: usize, buf_raw_capacity: usize) -> Self {
+        let mut vec = Self::new();
+        vec.raw_reserve_exact(buf_raw_capacity);
+        vec.storage.reserve_views(count);
+        vec
+    }
+
+    /// Whether the elements were moved to the heap.
+    #[inline]
+    pub fn spilled(&self) -> bool {
+        self.storage.spilled()
+    }
+
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub fn push<U: Unsize<T>>(&mut self, value: U) {
+        // The closure returns its argument
+        unsafe { self.push_coerce(value, |value| value) }
+    }
+
+    /// Appends `value`.
+    ///
+    /// # Safety
+    /// See [`PolyPtrVec::push_coerce`].
+    #[inline]
+    #[track_caller]
+    pub unsafe fn push_coerce<U, F: FnOnce(&mut U) -> &mut T>(&mut self, value: U, coerce: F) {
+        if let Err((_, err)) = self.try_push_coerce(value, coerce) {
+            err.handle()
+        }
+    }
+
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub fn insert<U: Unsize<T>>(&mut self, index: usize, element: U) {
+        // The closure returns its argument
+        unsafe { self.insert_coerce(index, element, |element| element) }
+    }
+
+    /// Inserts `element` at `index`.
+    ///
+    /// # Safety
+    /// See [`PolyPtrVec::push_coerce`].
+    #[inline]
+    #[track_caller]
+    pub unsafe fn insert_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &mut self,
+        index: usize,
+        element: U,
+        coerce: F,
+    ) {
+        if let Err((_, err)) = self.try_insert_coerce(index, element, coerce) {
+            err.handle()
+        }
+    }
+}
+
+/// Implementation of vec-like methods for inner raw buffer
+impl<T: ?Sized, S: PolyStorage<T>> PolyVec<T, S> {
+    #[inline]
+    pub fn raw_capacity(&self) -> usize {
+        self.storage.buf_capacity()
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn raw_reserve(&mut self, additional: usize) {
+        self.storage.reserve_buf(additional, 1);
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn raw_reserve_exact(&mut self, additional: usize) {
+        if let Err(err) = self.storage.try_reserve_buf(additional, 1, true) {
+            err.handle()
+        }
+    }
+
+    #[inline]
+    pub fn raw_try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        Ok(self.storage.try_reserve_buf(additional, 1, false)?)
+    }
+
+    #[inline]
+    pub fn raw_try_reserve_exact(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        Ok(self.storage.try_reserve_buf(additional, 1, true)?)
+    }
+
+    #[inline]
+    pub fn raw_shrink_to(&mut self, min_capacity: usize) {
+        self.storage.shrink_buf_to(min_capacity)
+    }
+}
+
+impl<T: ?Sized, S: PolyStorage<T>> PolyVec<T, S> {
+    #[inline(always)]
+    const fn from_storage(storage: S) -> Self {
+        Self {
+            storage,
+            sparse: false,
+            tombstones: Tombstones { count: 0, bytes: 0 },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Number of elements, not counting tombstones left by [`PolyVec::remove_deferred`].
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.storage.view_len() - self.tombstones.count
+    }
+
+    /// Number of elements including tombstones, which is the bound indices are checked
+    /// against. Same as [`PolyVec::len`] as long as there are no tombstones.
+    #[inline]
+    #[must_use]
+    pub fn len_with_tombstones(&self) -> usize {
+        self.storage.view_len()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn capacity<U>(&self) -> usize {
+        self.storage
+            .buf_capacity()
+            .checked_div(size_of::<U>())
+            .unwrap_or(usize::MAX)
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn reserve<U>(&mut self, additional: usize) {
+        self.storage
+            .reserve_buf(self.bytes_for::<U>(additional), align_of::<U>());
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn reserve_exact<U>(&mut self, additional: usize) {
+        if let Err(err) =
+            self.storage
+                .try_reserve_buf(self.bytes_for::<U>(additional), align_of::<U>(), true)
+        {
+            err.handle()
+        }
+    }
+
+    /// Without the `nightly` feature every `try_reserve*` error is reported as a capacity overflow.
+    #[inline]
+    pub fn try_reserve<U>(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        Ok(self
+            .storage
+            .try_reserve_buf(self.bytes_for::<U>(additional), align_of::<U>(), false)?)
+    }
+
+    #[inline]
+    pub fn try_reserve_exact<U>(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        Ok(self
+            .storage
+            .try_reserve_buf(self.bytes_for::<U>(additional), align_of::<U>(), true)?)
+    }
+
+    #[inline]
+    pub fn shrink_to_fit(&mut self) {
+        self.storage.shrink_buf_to(0)
+    }
+
+    #[inline]
+    pub fn shrink_to<U>(&mut self, min_capacity: usize) {
+        self.storage
+            .shrink_buf_to(min_capacity.saturating_mul(size_of::<U>()))
+    }
+
+    /// Shortens the vec to `len` elements including tombstones, dropping the rest.
+    #[inline]
+    pub fn truncate(&mut self, len: usize) {
+        let old_len = self.storage.view_len();
+        if len >= old_len {
+            return;
+        }
+        unsafe {
+            let views = slice::from_raw_parts(self.storage.view_ptr().add(len), old_len - len);
+            self.tombstones.forget(views);
+            if !self.fragmented() {
+                self.storage.set_buf_len(views[0].offset as usize);
+            } else if len > 0 {
+                // The elements may lie anywhere in `buf`, each leaves a hole
+                for view in views {
+                    self.release(self.bytes_of(view));
+                }
+            } else if let Some((free, _)) = self.storage.free_list_mut() {
+                free.clear();
+                self.storage.set_buf_len(0);
+            }
+            // Forget the tail before running destructors so a panic can't drop twice
+            self.storage.set_view_len(len);
+            drop_elements(self.storage.buf_mut_ptr(), views);
+        }
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.truncate(0)
+    }
+
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub fn push_within_capacity<U: Unsize<T>>(&mut self, value: U) -> Result<(), U> {
+        // The closure returns its argument
+        unsafe { self.push_within_capacity_coerce(value, |value| value) }
+    }
+
+    /// Appends `value` if it fits without reallocating, otherwise hands it back.
+    ///
+    /// # Safety
+    /// See [`PolyPtrVec::push_coerce`].
+    #[inline]
+    #[track_caller]
+    pub unsafe fn push_within_capacity_coerce<U, F: FnOnce(&mut U) -> &mut T>(
+        &mut self,
+        mut value: U,
+        coerce: F,
+    ) -> Result<(), U> {
+        let needed_space = self.bytes_for::<U>(1);
+        if self.storage.buf_capacity() - self.storage.buf_len() < needed_space
+            || align_of::<U>() > self.storage.base_align()
+            || self
+                .storage
+                .try_reserve_buf(0, align_of::<U>(), false)
+                .is_err()
+            || self.storage.try_reserve_views(1).is_err()
+        {
+            return Err(value);
+        }
+        let pointee = coerce_value(&mut value, coerce);
+        let view = self.push_value::<U>(value, pointee);
+        self.storage.push_view(view);
+        Ok(())
+    }
+
+    /// Switches sparse mode on or off, turning it off compacts the vec.
+    ///
+    /// In sparse mode removing an element only removes its view and leaves a hole
+    /// in the byte buffer instead of moving every element behind it. Holes are kept
+    /// in a free list by size and reused by later pushes of values that fit,
+    /// so the order of the elements follows the views rather than their position in memory.
+    /// Inserting, draining, retaining or truncating don't move any bytes either,
+    /// removed elements leave holes and inserted ones go into a hole or behind the rest.
+    ///
+    /// The free list is allocated by the storage, in the allocator of a [`PolyPtrVec`].
+    /// A [`SmallPolyVec`] moves its elements to the heap for it.
+    ///
+    /// # Panics
+    /// Panics when turned on for an [`InlinePolyVec`], which can't allocate a free list.
+    #[inline]
+    #[track_caller]
+    pub fn set_sparse(&mut self, sparse: bool) {
+        if sparse {
+            if self.storage.free_list_mut().is_none() {
+                panic!("sparse mode needs a storage that can allocate a free list");
+            }
+            self.sparse = true;
+        } else if self.sparse {
+            self.make_dense();
+            self.sparse = false;
+            if let Some((free, alloc)) = self.storage.free_list_mut() {
+                free.free(alloc);
+            }
+        }
+    }
+
+    #[inline]
+    pub fn is_sparse(&self) -> bool {
+        self.sparse
+    }
+
+    /// Number of bytes in holes left by removals in sparse mode.
+    #[inline]
+    pub fn hole_bytes(&self) -> usize {
+        self.storage.free_list().map_or(0, FreeList::bytes)
+    }
+
+    /// Number of tombstones left by [`PolyVec::remove_deferred`].
+    #[inline]
+    pub fn tombstones(&self) -> usize {
+        self.tombstones.count
+    }
+
+    /// Number of bytes still occupied by elements dropped by [`PolyVec::remove_deferred`].
+    #[inline]
+    pub fn tombstoned_bytes(&self) -> usize {
+        self.tombstones.bytes
+    }
+
+    /// Moves the elements next to each other in view order, squeezing out every hole
+    /// and tombstone. Elements behind a tombstone move down to a lower index.
+    ///
+    /// A dense vec is compacted in place in a single pass, a fragmented sparse vec is
+    /// copied once into a temporary buffer from the allocator of the storage
+    /// and from there back into place.
+    pub fn compact(&mut self) {
+        if self.tombstones.count > 0 || self.fragmented() {
+            self.squeeze(false)
+        }
+    }
+
+    /// Drops the element at `index` in O(1), leaving a tombstone behind.
+    ///
+    /// The tombstone keeps its index and its bytes until [`PolyVec::compact`], so the
+    /// indices of the other elements don't change. Iterators skip tombstones, while
+    /// [`PolyVec::get`] returns `None` for them. Removing a tombstone again does nothing.
+    /// [`PolyVec::len`] stops counting the element, indices are still bounded by
+    /// [`PolyVec::len_with_tombstones`].
+    #[track_caller]
+    pub fn remove_deferred(&mut self, index: usize) {
+        check_removal_index(index, self.storage.view_len());
+        if self.storage.views()[index].is_dead() {
+            return;
+        }
+        let ptr = self.element_ptr(index);
+        let size = unsafe { size_of_val(&*ptr) };
+        // The element counts as dropped before its destructor runs
+        self.storage.views_mut()[index].kill(size);
+        self.tombstones.count += 1;
+        self.tombstones.bytes += size;
+        unsafe { ptr::drop_in_place(ptr) }
+    }
+
+    /// Drops the element at `index`, or only removes it if it is a tombstone.
+    /// Use [`PolyVec::remove_boxed`] to take it out of the vec instead.
+    #[track_caller]
+    pub fn remove(&mut self, index: usize) {
+        check_removal_index(index, self.storage.view_len());
+        let view = &self.storage.views()[index];
+        if let Some(size) = view.dead_size() {
+            self.tombstones.forget(slice::from_ref(view));
+            if self.sparse {
+                unsafe { self.leave_hole(index, size) };
+            } else {
+                self.close_gap(index);
+            }
+            return;
+        }
+        let ptr = self.element_ptr(index);
+        if self.sparse {
+            // The element is gone from the vec before its destructor runs
+            unsafe { self.leave_hole(index, size_of_val(&*ptr)) };
+            unsafe { ptr::drop_in_place(ptr) };
+            return;
+        }
+        let _guard = RemoveGuard { vec: self, index };
+        unsafe { ptr::drop_in_place(ptr) }
+    }
+
+    /// Removes the element at `index` and moves it into a new allocation.
+    ///
+    /// # Panics
+    /// Panics if `index` is out of bounds or a tombstone.
+    #[track_caller]
+    pub fn remove_boxed(&mut self, index: usize) -> Box<T> {
+        check_removal_index(index, self.storage.view_len());
+        self.check_live(index);
+        let value = unsafe { self.box_element(index) };
+        if self.sparse {
+            unsafe { self.leave_hole(index, size_of_val(&*value)) };
+        } else {
+            self.close_gap(index);
+        }
+        value
+    }
+
+    /// Removes the elements in `range` from the vec, returning them as boxes.
+    ///
+    /// The elements behind the range are moved down once when the iterator is dropped.
+    /// In a fragmented sparse vec only their views move and the drained bytes become holes.
+    /// Elements that weren't consumed by then are dropped, tombstones are skipped.
+    #[track_caller]
+    pub fn drain<R: RangeBounds<usize>>(&mut self, range: R) -> Drain<'_, T, S> {
+        let len = self.storage.view_len();
+        let Range { start, end } = slice_range(range, len);
+        let buf_len = self.storage.buf_len();
+        let views =
+            unsafe { slice::from_raw_parts(self.storage.view_ptr().add(start), end - start) };
+        let remaining = views.iter().filter(|view| !view.is_dead()).count();
+        self.tombstones.forget(views);
+        let fragmented = self.fragmented();
+        if fragmented {
+            // Nothing writes to `buf` while it is borrowed, so the drained
+            // elements stay readable in their holes
+            for view in views {
+                self.release(self.bytes_of(view));
+            }
+        }
+        unsafe {
+            // If the iterator is leaked, the vec just forgets the range and its tail
+            self.storage.set_view_len(start);
+            if start < len && !fragmented {
+                self.storage
+                    .set_buf_len(self.storage.view_ptr().add(start).read().offset as usize);
+            }
+        }
+        Drain {
+            vec: self,
+            start,
+            end,
+            len,
+            buf_len,
+            front: start,
+            back: end,
+            remaining,
+        }
+    }
+
+    /// Keeps only the elements for which `f` returns `true`, dropping the rest.
+    /// See [`PolyVec::retain_mut`].
+    #[inline]
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        self.retain_mut(|value| f(value))
+    }
+
+    /// Keeps only the elements for which `f` returns `true`, dropping the rest.
+    /// Tombstones are removed without calling `f`.
+    ///
+    /// Kept elements are slid down within `buf` in a single pass, in sparse mode they stay
+    /// where they are and removed ones leave holes. If `f` or a destructor panics,
+    /// the elements that weren't visited yet are moved down behind the kept ones,
+    /// so the vec stays valid.
+    #[inline]
+    pub fn retain_mut<F: FnMut(&mut T) -> bool>(&mut self, mut f: F) {
+        self.retain_views(|_, value| f(value))
+    }
+
+    /// [`PolyVec::retain_mut`] with access to the view of each element.
+    fn retain_views<F: FnMut(&OffsetDynView<T>, &mut T) -> bool>(&mut self, mut f: F) {
+        let fragmented = self.fragmented();
+        let original_len = self.storage.view_len();
+        // Views are only restored by the guard, which is enough to stay sound if it is leaked
+        unsafe { self.storage.set_view_len(0) };
+
+        let mut guard = RetainGuard {
+            vec: self,
+            processed: 0,
+            deleted: 0,
+            original_len,
+            write_end: 0,
+            fragmented,
+        };
+
+        while guard.processed < original_len {
+            unsafe {
+                let base = guard.vec.storage.buf_mut_ptr();
+                let views = guard.vec.storage.view_mut_ptr();
+                let view = views.add(guard.processed);
+                if (*view).is_dead() {
+                    guard.vec.tombstones.forget(slice::from_ref(&*view));
+                    if fragmented {
+                        guard.vec.release(guard.vec.bytes_of(&*view));
+                    }
+                    guard.processed += 1;
+                    guard.deleted += 1;
+                    continue;
+                }
+                let value = (*view).as_mut_view(base as *mut ()).inner().as_ptr();
+                if !f(&*view, &mut *value) {
+                    if fragmented {
+                        guard.vec.release(guard.vec.bytes_of(&*view));
+                    }
+                    guard.processed += 1;
+                    guard.deleted += 1;
+                    ptr::drop_in_place(value);
+                    continue;
+                }
+
+                // Kept elements of a fragmented vec stay where they are
+                if !fragmented {
+                    let layout = Layout::for_value(&*value);
+                    let offset = align_up(guard.write_end, layout.align());
+                    if offset != (*view).offset as usize {
+                        ptr::copy(value as *const u8, base.add(offset), layout.size());
+                        (*view).offset = offset as isize;
+                    }
+                    guard.write_end = offset + layout.size();
+                }
+                if guard.deleted > 0 {
+                    ptr::copy_nonoverlapping(view, views.add(guard.processed - guard.deleted), 1);
+                }
+                guard.processed += 1;
+            }
+        }
+    }
+
+    /// Removes the last element and moves it into a new allocation.
+    /// Tombstones at the end of the vec are removed on the way.
+    #[inline]
+    pub fn pop(&mut self) -> Option<Box<T>> {
+        self.discard_dead_tail();
+        if self.storage.view_len() == 0 {
+            None
+        } else {
+            Some(self.remove_boxed(self.storage.view_len() - 1))
+        }
+    }
+
+    /// Removes the element at `index` and moves the last element into its place.
+    ///
+    /// The last element is copied into the freed bytes when it fits there, otherwise
+    /// the elements in between move up once to make room for it.
+    /// In sparse mode only the views are swapped and the element leaves a hole.
+    /// Tombstones at the end of the vec are removed first.
+    ///
+    /// # Panics
+    /// Panics if `index` is out of bounds or a tombstone.
+    #[track_caller]
+    pub fn swap_remove_boxed(&mut self, index: usize) -> Box<T> {
+        check_removal_index(index, self.storage.view_len());
+        self.check_live(index);
+        self.discard_dead_tail();
+        let len = self.storage.view_len();
+        let value = unsafe { self.box_element(index) };
+        let last = len - 1;
+        if self.sparse {
+            // Only the views swap, the element leaves a hole
+            self.storage.views_mut().swap(index, last);
+            if index != last {
+                self.mark_fragmented();
+            }
+            unsafe { self.leave_hole(last, size_of_val(&*value)) };
+            return value;
+        }
+        if index == last {
+            self.close_gap(index);
+            return value;
+        }
+
+        unsafe {
+            let layout = Layout::for_value(&*self.element_ptr(last));
+            let start = self.storage.views()[index].offset as usize;
+            let next = self.storage.views()[index + 1].offset as usize;
+            let last_offset = self.storage.views()[last].offset as usize;
+            let offset = align_up(start, layout.align());
+            let end = if offset + layout.size() <= next || index + 1 == last {
+                // Nothing has to move out of the way of the last element
+                let base = self.storage.buf_mut_ptr();
+                ptr::copy(base.add(last_offset), base.add(offset), layout.size());
+                if index + 1 == last {
+                    offset + layout.size()
+                } else {
+                    last_offset
+                }
+            } else {
+                // Like in `try_make_room`, the elements in between move by a multiple
+                // of the largest alignment in the buffer
+                let mid_end = self.end_before(last);
+                let align = self.storage.buf_align().max(layout.align());
+                let shift = align_up(offset + layout.size() - next, align);
+                let mut src = last_offset;
+                if mid_end + shift > last_offset {
+                    // The shifted elements would overwrite the last one, so it waits
+                    // behind them, still in the buffer
+                    let buf_len = self.storage.buf_len();
+                    src = align_up(buf_len.max(mid_end + shift), layout.align());
+                    self.storage
+                        .reserve_buf(src + layout.size() - buf_len, layout.align());
+                    let base = self.storage.buf_mut_ptr();
+                    ptr::copy_nonoverlapping(base.add(last_offset), base.add(src), layout.size());
+                }
+                let base = self.storage.buf_mut_ptr();
+                ptr::copy(base.add(next), base.add(next + shift), mid_end - next);
+                ptr::copy_nonoverlapping(base.add(src), base.add(offset), layout.size());
+                for view in &mut self.storage.views_mut()[index + 1..last] {
+                    view.offset += shift as isize;
+                }
+                mid_end + shift
+            };
+            let mut view = self.storage.pop_view().unwrap_unchecked();
+            view.offset = offset as isize;
+            self.storage.views_mut()[index] = view;
+            self.storage.set_buf_len(end);
+        }
+        value
+    }
+
+    #[inline]
+    pub fn iter(&self) -> OffsettingIter<'_, T> {
+        OffsettingIter::new(self)
+    }
+
+    #[inline]
+    pub fn iter_mut(&mut self) -> OffsettingIterMut<'_, T> {
+        OffsettingIterMut::new(self)
+    }
+
+    /// Returns `None` if `index` is out of bounds or a tombstone.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if self
+            .storage
+            .views()
+            .get(index)
+            .is_some_and(|view| !view.is_dead())
+        {
+            Some(unsafe { self.get_unchecked(index) })
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if self
+            .storage
+            .views()
+            .get(index)
+            .is_some_and(|view| !view.is_dead())
+        {
+            Some(unsafe { self.get_unchecked_mut(index) })
+        } else {
+            None
+        }
+    }
+
+    /// # Safety
+    /// `index` must be less than [`PolyVec::len_with_tombstones`] and not be a tombstone.
+    #[inline]
+    pub unsafe fn get_unchecked(&self, index: usize) -> &T {
+        self.storage
+            .views()
+            .get_unchecked(index)
+            .as_view(self.storage.buf_ptr() as *const ())
+            .into_inner()
+    }
+
+    /// # Safety
+    /// `index` must be less than [`PolyVec::len_with_tombstones`] and not be a tombstone.
+    #[inline]
+    pub unsafe fn get_unchecked_mut(&mut self, index: usize) -> &mut T {
+        let base = self.storage.buf_mut_ptr() as *mut ();
+        self.storage
+            .views()
+            .get_unchecked(index)
+            .as_mut_view(base)
+            .into_inner_mut()
+    }
+
+    /// First element that isn't a tombstone.
+    #[inline]
+    pub fn first(&self) -> Option<&T> {
+        self.iter().next()
+    }
+
+    #[inline]
+    pub fn first_mut(&mut self) -> Option<&mut T> {
+        self.iter_mut().next()
+    }
+
+    /// Last element that isn't a tombstone.
+    #[inline]
+    pub fn last(&self) -> Option<&T> {
+        self.iter().next_back()
+    }
+
+    #[inline]
+    pub fn last_mut(&mut self) -> Option<&mut T> {
+        self.iter_mut().next_back()
+    }
+
+    #[inline]
+    #[track_caller]
+    fn check_live(&self, index: usize) {
+        if self.storage.views()[index].is_dead() {
+            missing_element(index, self.storage.view_len());
+        }
+    }
+}
+
+/// Access to the concrete type of the elements.
+///
+/// Every element records its type with lifetimes erased when it is pushed or inserted.
+/// Values coerced to a `'static` `T` are `'static` themselves, so for these vecs the
+/// recorded type is exact.
+impl<T: ?Sized + 'static, S: PolyStorage<T>> PolyVec<T, S> {
+    /// Whether the element at `index` is a `U`. Tombstones aren't anything.
+    #[inline]
+    pub fn is<U: 'static>(&self, index: usize) -> bool {
+        self.storage
+            .views()
+            .get(index)
+            .is_some_and(OffsetDynView::holds::<U>)
+    }
+
+    /// Returns the element at `index` if it is a `U`.
+    #[inline]
+    pub fn get_as<U: 'static>(&self, index: usize) -> Option<&U> {
+        if !self.is::<U>(index) {
+            return None;
+        }
+        let offset = self.storage.views()[index].offset as usize;
+        Some(unsafe { &*(self.storage.buf_ptr().add(offset) as *const U) })
+    }
+
+    #[inline]
+    pub fn get_as_mut<U: 'static>(&mut self, index: usize) -> Option<&mut U> {
+        if !self.is::<U>(index) {
+            return None;
+        }
+        let offset = self.storage.views()[index].offset as usize;
+        Some(unsafe { &mut *(self.storage.buf_mut_ptr().add(offset) as *mut U) })
+    }
+
+    /// Removes the element at `index` and returns it by value if it is a `U`,
+    /// otherwise the vec is left as is.
+    ///
+    /// # Panics
+    /// Panics if `index` is out of bounds.
+    #[track_caller]
+    pub fn remove_as<U: 'static>(&mut self, index: usize) -> Option<U> {
+        check_removal_index(index, self.storage.view_len());
+        if !self.is::<U>(index) {
+            return None;
+        }
+        let offset = self.storage.views()[index].offset as usize;
+        let value = unsafe { ptr::read(self.storage.buf_ptr().add(offset) as *const U) };
+        if self.sparse {
+            unsafe { self.leave_hole(index, size_of::<U>()) };
+        } else {
+            self.close_gap(index);
+        }
+        Some(value)
+    }
+
+    /// Iterates over the elements that are a `U`, in order.
+    #[inline]
+    pub fn iter_of<U: 'static>(&self) -> IterOf<'_, T, U> {
+        IterOf {
+            base: self.storage.buf_ptr(),
+            iter: self.storage.views().iter(),
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn iter_of_mut<U: 'static>(&mut self) -> IterOfMut<'_, T, U> {
+        IterOfMut {
+            base: self.storage.buf_mut_ptr(),
+            iter: self.storage.views().iter(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Number of elements that are a `U`.
+    #[inline]
+    pub fn count_of<U: 'static>(&self) -> usize {
+        self.storage
+            .views()
+            .iter()
+            .filter(|view| view.holds::<U>())
+            .count()
+    }
+
+    #[inline]
+    pub fn first_of<U: 'static>(&self) -> Option<&U> {
+        self.iter_of().next()
+    }
+
+    /// Drops every element that is a `U` and returns how many there were.
+    /// The other elements are moved down like by [`PolyVec::retain`], which also
+    /// removes tombstones.
+    pub fn remove_all_of<U: 'static>(&mut self) -> usize {
+        let mut removed = 0;
+        self.retain_views(|view, _| {
+            let keep = !view.holds::<U>();
+            removed += usize::from(!keep);
+            keep
+        });
+        removed
+    }
+}
+
+impl<T: ?Sized, S: PolyStorage<T>> IntoIterator for PolyVec<T, S> {
+    type Item = Box<T>;
+    type IntoIter = IntoIter<T, S>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        let back = self.len_with_tombstones();
+        let remaining = self.len();
+        IntoIter {
+            vec: self,
+            front: 0,
+            back,
+            remaining,
+        }
+    }
+}
+
+impl<'a, T: ?Sized, S: PolyStorage<T>> IntoIterator for &'a PolyVec<T, S> {
+    type Item = &'a T;
+    type IntoIter = OffsettingIter<'a, T>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T: ?Sized, S: PolyStorage<T>> IntoIterator for &'a mut PolyVec<T, S> {
+    type Item = &'a mut T;
+    type IntoIter = OffsettingIterMut<'a, T>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+#[inline]
+#[track_caller]
+fn check_removal_index(index: usize, len: usize) {
+    #[cold]
+    #[inline(never)]
+    #[track_caller]
+    fn assert_failed(index: usize, len: usize) -> ! {
+        panic!("removal index (is {index}) should be < len (is {len})");
+    }
+
+    if index >= len {
+        assert_failed(index, len);
+    }
+}
+
+/// Resolves `range` against a vec of `len` elements, panicking like slices do.
+#[track_caller]
+fn slice_range<R: RangeBounds<usize>>(range: R, len: usize) -> Range<usize> {
+    let start = match range.start_bound() {
+        Bound::Included(&start) => start,
+        Bound::Excluded(start) => start
+            .checked_add(1)
+            .unwrap_or_else(|| panic!("attempted to index slice from after maximum usize")),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(end) => end
+            .checked_add(1)
+            .unwrap_or_else(|| panic!("attempted to index slice up to maximum usize")),
+        Bound::Excluded(&end) => end,
+        Bound::Unbounded => len,
+    };
+    if start > end {
+        panic!("slice index starts at {start} but ends at {end}");
+    }
+    if end > len {
+        panic!("range end index {end} out of range for slice of length {len}");
+    }
+    start..end
+}
+
+#[cold]
+#[inline(never)]
+#[track_caller]
+fn missing_element(index: usize, len: usize) -> ! {
+    if index < len {
+        panic!("element at index {index} was removed");
+    }
+    panic!("index out of bounds: the len is {len} but the index is {index}");
+}
+
+impl<T: ?Sized, S: PolyStorage<T>> Index<usize> for PolyVec<T, S> {
+    type Output = T;
+
+    #[inline]
+    #[track_caller]
+    fn index(&self, index: usize) -> &T {
+        match self.get(index) {
+            Some(value) => value,
+            None => missing_element(index, self.len_with_tombstones()),
+        }
+    }
+}
+
+impl<T: ?Sized, S: PolyStorage<T>> IndexMut<usize> for PolyVec<T, S> {
+    #[inline]
+    #[track_caller]
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        let len = self.len_with_tombstones();
+        match self.get_mut(index) {
+            Some(value) => value,
+            None => missing_element(index, len),
+        }
+    }
+}
+
+// The storage only hands out its own pointers, which is all dropping the elements needs
+// even if borrows held by `T` or the allocator of `S` are already dangling.
+// Storages release their memory when they are dropped themselves, also after a panic here.
+#[cfg(feature = "nightly")]
+unsafe impl<#[may_dangle] T: ?Sized, #[may_dangle] S: PolyStorage<T>> Drop for PolyVec<T, S> {
+    fn drop(&mut self) {
+        unsafe { drop_elements(self.storage.buf_mut_ptr(), self.storage.views()) }
+    }
+}
+
+#[cfg(not(feature = "nightly"))]
+impl<T: ?Sized, S: PolyStorage<T>> Drop for PolyVec<T, S> {
+    fn drop(&mut self) {
+        unsafe { drop_elements(self.storage.buf_mut_ptr(), self.storage.views()) }
+    }
+}
+
+/// Values that can be cloned into raw memory, which makes a [`PolyPtrVec`] of them [`Clone`].
+///
+/// Implemented for every [`Clone`] type. Trait objects get it by making it a supertrait:
+///
+/// ```
+/// use std::fmt::Debug;
+/// use trait_vec::poly_push;
+/// use trait_vec::trait_vec::{PolyClone, PolyPtrVec};
+///
+/// trait Command: PolyClone + Debug {}
+/// impl Command for String {}
+///
+/// let mut vec = PolyPtrVec::<dyn Command>::new();
+/// poly_push!(vec, String::from("undo"));
+/// let snapshot = vec.clone();
+/// assert_eq!(format!("{:?}", &snapshot[0]), "\"undo\"");
+/// ```
+///
+/// # Safety
+/// [`PolyClone::clone_to`] has to initialize the bytes behind `dst` with a value of the same
+/// type as `self`, with the same size and alignment.
+pub unsafe trait PolyClone {
+    /// Writes a clone of `self` to `dst`.
+    ///
+    /// # Safety
+    /// `dst` must be valid for writes of `size_of_val(self)` bytes and aligned for `self`.
+    unsafe fn clone_to(&self, dst: *mut u8);
+}
+
+unsafe impl<U: Clone> PolyClone for U {
+    #[inline]
+    unsafe fn clone_to(&self, dst: *mut u8) {
+        ptr::write(dst as *mut U, self.clone())
+    }
+}
+
+/// Clones every element to the same offset of a new buffer, so the view table is copied as is.
+/// Holes and tombstones are carried over without touching their bytes.
+impl<T: ?Sized + PolyClone, A: Allocator + Clone> Clone for PolyPtrVec<T, A> {
+    fn clone(&self) -> Self {
+        let storage = HeapStorage::with_capacity_in(
+            self.storage.view_len(),
+            self.storage.buf_len(),
+            self.storage.buf_align(),
+            self.storage.allocator().clone(),
+        );
+        let mut vec = Self::from_storage(storage);
+        unsafe {
+            // Only elements with a view are dropped if a clone panics
+            vec.storage.set_buf_len(self.storage.buf_len());
+            let base = vec.storage.buf_mut_ptr();
+            for view in self.storage.views() {
+                if !view.is_dead() {
+                    let value = view.as_view(self.storage.buf_ptr() as *const ());
+                    value.clone_to(base.add(view.offset as usize));
+                }
+                vec.storage.push_view(view.clone());
+            }
+        }
+        if let (Some(src), Some((dst, alloc))) =
+            (self.storage.free_list(), vec.storage.free_list_mut())
+        {
+            *dst = src.clone_in(alloc);
+        }
+        vec.sparse = self.sparse;
+        vec.tombstones = self.tombstones.clone();
+        vec
+    }
+}
+
+/// Appends a value to a [`PolyPtrVec`], coercing it to the element type.
+///
+/// Works on stable Rust, where `PolyPtrVec::push` isn't available. On an [`InlinePolyVec`]
+/// it evaluates to the `Result` of [`InlinePolyVec::push_coerce`].
+///
+/// ```
+/// use std::fmt::Debug;
+/// use trait_vec::poly_push;
+/// use trait_vec::trait_vec::PolyPtrVec;
+///
+/// let mut vec = PolyPtrVec::<dyn Debug>::new();
+/// poly_push!(vec, 1u8);
+/// poly_push!(vec, String::from("two"));
+/// assert_eq!(vec.len(), 2);
+/// ```
+///
+/// Its arguments are evaluated outside of the `unsafe` block that calls `push_coerce`:
+///
+/// ```compile_fail,E0133
+/// # use std::fmt::Debug;
+/// # use trait_vec::poly_push;
+/// # use trait_vec::trait_vec::PolyPtrVec;
+/// let mut vec = PolyPtrVec::<dyn Debug>::new();
+/// let ptr = &1u8 as *const u8;
+/// poly_push!(vec, *ptr);
+/// ```
+#[macro_export]
+macro_rules! poly_push {
+    ($vec:expr, $value:expr $(,)?) => {
+        match ($vec.__receiver(), $value) {
+            // The closure returns its argument
+            (vec, value) => unsafe { vec.push_coerce(value, |value| value) },
+        }
+    };
+}
+
+/// Appends every value of an iterator to a [`PolyPtrVec`], coercing them to the element type.
+///
+/// Works on stable Rust, where `PolyPtrVec` implements neither `Extend` nor `FromIterator`.
+///
+/// ```
+/// use std::fmt::Debug;
+/// use trait_vec::poly_extend;
+/// use trait_vec::trait_vec::PolyPtrVec;
+///
+/// let mut vec = PolyPtrVec::<dyn Debug>::new();
+/// poly_extend!(vec, 0..3u16);
+/// poly_extend!(vec, ["three", "four"]);
+/// assert_eq!(vec.len(), 5);
+/// ```
+#[macro_export]
+macro_rules! poly_extend {
+    ($vec:expr, $iter:expr $(,)?) => {
+        match ($vec.__receiver(), $iter) {
+            // The closure returns its argument
+            (vec, iter) => unsafe { vec.extend_coerce(iter, |value| value) },
+        }
+    };
+}
+
+/// Inserts a value into a [`PolyPtrVec`] at an index, or into a
+/// [`PolySlotMap`](crate::slot_map::PolySlotMap), coercing it to the element type.
+///
+/// Works on stable Rust, where `PolyPtrVec::insert` and `PolySlotMap::insert` aren't available.
+#[macro_export]
+macro_rules! poly_insert {
+    ($vec:expr, $index:expr, $value:expr $(,)?) => {
+        match ($vec.__receiver(), $index, $value) {
+            // The closure returns its argument
+            (vec, index, value) => unsafe { vec.insert_coerce(index, value, |value| value) },
+        }
+    };
+    ($map:expr, $value:expr $(,)?) => {
+        match ($map.__receiver(), $value) {
+            // The closure returns its argument
+            (map, value) => unsafe { map.insert_coerce(value, |value| value) },
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::allocator::AllocError;
+    use crate::test_support::{assert_drops_once, Align16, Align64, DropCounter};
+    use core::ptr::NonNull;
+    use std::cell::Cell;
+    use std::fmt::Debug;
+    use std::mem::size_of;
+    use std::rc::Rc;
+
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    struct Example {
+        inner: f64,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    struct Point {
+        x: f64,
+        y: f64,
+        z: f64,
+    }
+
+    impl Example {
+        pub fn new(value: f64) -> Self {
+            Self { inner: value }
+        }
+    }
+
+    impl Point {
+        pub fn new(x: f64, y: f64, z: f64) -> Self {
+            Self { x, y, z }
+        }
+    }
+
+    fn assert_aligned<S: PolyStorage<dyn Debug>>(vec: &PolyVec<dyn Debug, S>) {
+        for value in vec.iter() {
+            let addr = (value as *const dyn Debug).addr();
+            assert_eq!(
+                addr % std::mem::align_of_val(value),
+                0,
+                "{:?} is misaligned",
+                value
+            );
+        }
+    }
+
+    fn names<S: PolyStorage<dyn Debug>>(vec: &PolyVec<dyn Debug, S>) -> Vec<String> {
+        vec.iter().map(|value| format!("{:?}", value)).collect()
+    }
+
+    fn push_within<S: PolyStorage<dyn Debug>, U: Debug + 'static>(
+        vec: &mut PolyVec<dyn Debug, S>,
+        value: U,
+    ) -> Result<(), U> {
+        unsafe { vec.push_within_capacity_coerce(value, |value| value as &mut dyn Debug) }
+    }
+
+    #[test]
+    fn push_without_resize() {
+        let mut vec = PolyPtrVec::<dyn Debug>::with_capacity::<Point>(4);
+        poly_push!(vec, Example::new(std::f64::consts::E));
+        poly_push!(vec, Example::new(std::f64::consts::PI));
+        poly_push!(vec, Point::new(0.5, 0.0, 1.7));
+        poly_push!(vec, Example::new(std::f64::consts::SQRT_2));
+
+        let mut last_addr: Option<usize> = None;
+        for (index, view) in vec.iter().enumerate() {
+            let pointer = view as *const dyn Debug;
+            let current_addr = pointer.addr();
+            let diff = if let Some(addr) = last_addr {
+                current_addr - addr
+            } else {
+                0
+            };
+
+            let size_of_example = size_of::<Example>() + align_of::<Example>();
+            println!(
+                "{}: Value: {:?} | Addr: {} | Addr diff {} | Proper diff {}",
+                index, view, current_addr, diff, size_of_example
+            );
+
+            last_addr = Some(current_addr);
+        }
+    }
+
+    #[test]
+    fn push_with_resize() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, Example::new(std::f64::consts::E));
+        poly_push!(vec, Example::new(std::f64::consts::PI));
+        poly_push!(vec, Point::new(0.5, 0.0, 1.7));
+        poly_push!(vec, Example::new(std::f64::consts::SQRT_2));
+        poly_push!(vec, Point::new(0.25, 0.5, 1.5));
+        poly_push!(vec, Point::new(0.5, 0.5, 1.35));
+
+        let mut last_addr: Option<usize> = None;
+        for (index, view) in vec.iter().enumerate() {
+            let pointer = view as *const dyn Debug;
+            let current_addr = pointer.addr();
+            let diff = if let Some(addr) = last_addr {
+                current_addr - addr
+            } else {
+                0
+            };
+
+            let size_of_example = size_of::<Example>() + align_of::<Example>();
+            println!(
+                "{}: Value: {:?} | Addr: {} | Addr diff {} | Proper diff {}",
+                index, view, current_addr, diff, size_of_example
+            );
+
+            last_addr = Some(current_addr);
+        }
+    }
+
+    #[test]
+    fn push_within_capacity() {
+        let mut vec = PolyPtrVec::<dyn Debug>::with_capacity::<Example>(3);
+        assert!(push_within(&mut vec, Example::new(std::f64::consts::E)).is_ok());
+        assert!(push_within(&mut vec, Example::new(std::f64::consts::PI)).is_ok());
+        assert!(push_within(&mut vec, Example::new(std::f64::consts::SQRT_2)).is_ok());
+        assert!(push_within(&mut vec, Example::new(std::f64::consts::E)).is_err());
+        assert!(push_within(&mut vec, Example::new(std::f64::consts::E)).is_err());
+    }
+
+    #[test]
+    fn slice() {
+        let mut vec = PolyPtrVec::<[usize]>::with_capacity::<[usize; 25]>(1);
+        poly_push!(vec, [3; 10]);
+        poly_push!(vec, [10; 15]);
+
+        let result = vec.iter().flatten().sum::<usize>();
+        assert_eq!(180, result);
+    }
+
+    #[test]
+    fn drop_runs_destructors() {
+        assert_drops_once(|counter| {
+            let mut vec = PolyPtrVec::<dyn Debug>::new();
+            poly_push!(vec, counter());
+            poly_push!(vec, String::from("heap"));
+            poly_push!(vec, counter());
+            poly_push!(vec, vec![1u8, 2, 3]);
+            vec
+        });
+    }
+
+    #[test]
+    fn truncate_and_clear_drop_elements() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, DropCounter::new(&drops, "a"));
+        poly_push!(vec, Example::new(1.0));
+        poly_push!(vec, DropCounter::new(&drops, "b"));
+        poly_push!(vec, DropCounter::new(&drops, "c"));
+
+        vec.truncate(5);
+        assert_eq!(drops.get(), 0);
+        vec.truncate(2);
+        assert_eq!(drops.get(), 2);
+        assert_eq!(vec.len(), 2);
+
+        poly_push!(vec, DropCounter::new(&drops, "d"));
+        assert_eq!(vec.len(), 3);
+        vec.clear();
+        assert_eq!(drops.get(), 4);
+        assert!(vec.is_empty());
+        assert_eq!(vec.iter().count(), 0);
+    }
+
+    #[test]
+    fn remove_drops_element() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, String::from("first"));
+        poly_push!(vec, DropCounter::new(&drops, "second"));
+        poly_push!(vec, Point::new(1.0, 2.0, 3.0));
+        poly_push!(vec, String::from("last"));
+
+        vec.remove(1);
+        assert_eq!(drops.get(), 1);
+        assert_eq!(
+            names(&vec),
+            ["\"first\"", "Point { x: 1.0, y: 2.0, z: 3.0 }", "\"last\""]
+        );
+
+        vec.remove(2);
+        vec.remove(0);
+        assert_eq!(names(&vec), ["Point { x: 1.0, y: 2.0, z: 3.0 }"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "removal index (is 1) should be < len (is 1)")]
+    fn remove_out_of_bounds() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, Example::new(1.0));
+        vec.remove(1);
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn borrowed_elements_may_dangle() {
+        let mut vec = PolyPtrVec::<[&String]>::new();
+        let value = String::from("borrowed");
+        poly_push!(vec, [&value]);
+        assert_eq!(vec.iter().flatten().count(), 1);
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn unsize_push_and_insert() {
+        let mut vec = PolyPtrVec::<dyn Debug>::with_capacity::<u64>(1);
+        assert!(vec.push_within_capacity(1u64).is_ok());
+        assert!(vec.push_within_capacity(2u8).is_err());
+        vec.push(String::from("three"));
+        vec.insert(0, Align16(4));
+        assert_eq!(names(&vec), ["Align16(4)", "1", "\"three\""]);
+        assert_aligned(&vec);
+    }
+
+    #[test]
+    fn push_coerce_with_closure() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        unsafe {
+            vec.push_coerce(1u8, |value| value as &mut dyn Debug);
+            vec.push_coerce(Align64(2), |value| value);
+            vec.insert_coerce(1, String::from("three"), |value| value);
+        }
+        poly_insert!(vec, 0, Point::new(4.0, 5.0, 6.0));
+        assert_eq!(
+            names(&vec),
+            [
+                "Point { x: 4.0, y: 5.0, z: 6.0 }",
+                "1",
+                "\"three\"",
+                "Align64(2)"
+            ]
+        );
+        assert_aligned(&vec);
+
+        let mut slices = PolyPtrVec::<[u16]>::new();
+        unsafe { slices.push_coerce([1, 2, 3], |value| value) };
+        poly_push!(slices, [4; 2]);
+        assert_eq!(slices.iter().flatten().sum::<u16>(), 14);
+    }
+
+    #[test]
+    #[should_panic(expected = "`coerce` has to return the value it was given")]
+    fn push_coerce_rejects_other_values() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        // A field at another offset is caught before anything is stored
+        unsafe { vec.push_coerce((1u8, 2u64), |value| &mut value.1) };
+    }
+
+    #[test]
+    fn mixed_alignment() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        for round in 0..8u8 {
+            poly_push!(vec, round);
+            poly_push!(vec, round as u64);
+            poly_push!(vec, Align16(round));
+            poly_push!(vec, Align64(round as u16));
+            poly_push!(vec, [round; 3]);
+            assert_aligned(&vec);
+        }
+        assert_eq!(vec.len(), 40);
+        assert_eq!(
+            names(&vec)[..5],
+            ["0", "0", "Align16(0)", "Align64(0)", "[0, 0, 0]"]
+        );
+        assert_eq!(
+            names(&vec)[35..],
+            ["7", "7", "Align16(7)", "Align64(7)", "[7, 7, 7]"]
+        );
+    }
+
+    #[test]
+    fn alignment_grows_with_buffer() {
+        let mut vec = PolyPtrVec::<dyn Debug>::with_capacity::<u8>(4);
+        poly_push!(vec, 1u8);
+        poly_push!(vec, 2u8);
+        poly_push!(vec, 3u64);
+        assert_aligned(&vec);
+        poly_push!(vec, Align64(4));
+        assert_aligned(&vec);
+        assert_eq!(names(&vec), ["1", "2", "3", "Align64(4)"]);
+    }
+
+    #[test]
+    fn insert_and_remove_keep_alignment() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, 2u64);
+        poly_push!(vec, Align16(3));
+        poly_insert!(vec, 1, Align64(4));
+        poly_insert!(vec, 0, 5u8);
+        poly_insert!(vec, 3, 6u16);
+        assert_aligned(&vec);
+        assert_eq!(
+            names(&vec),
+            ["5", "1", "Align64(4)", "6", "2", "Align16(3)"]
+        );
+
+        vec.remove(0);
+        assert_aligned(&vec);
+        vec.remove(1);
+        assert_aligned(&vec);
+        poly_insert!(vec, 4, 7u8);
+        vec.remove(2);
+        assert_aligned(&vec);
+        assert_eq!(names(&vec), ["1", "6", "Align16(3)", "7"]);
+    }
+
+    #[test]
+    fn removal_frees_whole_gap_after_large_alignment() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, Align64(1));
+        vec.remove(0);
+        for value in 0..4u8 {
+            poly_push!(vec, value);
+        }
+        for value in 0..100_000u32 {
+            poly_push!(vec, value as u8);
+            vec.remove(0);
+        }
+        assert_eq!(vec.len(), 4);
+        assert!(vec.raw_capacity() <= 64, "{}", vec.raw_capacity());
+        poly_push!(vec, Align16(2));
+        vec.drain(..2);
+        vec.retain(|value| format!("{value:?}") != "0");
+        assert_aligned(&vec);
+
+        let mut inline = InlinePolyVec::<dyn Debug, 16>::new();
+        poly_push!(inline, Align16(3)).unwrap();
+        inline.remove(0);
+        for value in 0..1000u32 {
+            poly_push!(inline, value as u8).unwrap();
+            if inline.len() > 4 {
+                inline.remove(0);
+            }
+        }
+        assert_eq!(inline.len(), 4);
+    }
+
+    #[test]
+    fn push_within_capacity_respects_alignment() {
+        let mut vec = PolyPtrVec::<dyn Debug>::with_capacity::<u64>(4);
+        assert!(push_within(&mut vec, 1u8).is_ok());
+        assert!(push_within(&mut vec, Align16(2)).is_err());
+        assert!(push_within(&mut vec, 3u64).is_ok());
+        assert!(push_within(&mut vec, 4u64).is_ok());
+        assert!(push_within(&mut vec, 5u64).is_ok());
+        assert!(push_within(&mut vec, 6u64).is_err());
+        assert_aligned(&vec);
+    }
+
+    #[test]
+    fn indexed_access() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        assert!(vec.first().is_none());
+        assert!(vec.last_mut().is_none());
+        poly_push!(vec, Example::new(1.0));
+        poly_push!(vec, Align64(2));
+        poly_push!(vec, String::from("three"));
+
+        assert_eq!(format!("{:?}", &vec[0]), "Example { inner: 1.0 }");
+        assert_eq!(format!("{:?}", vec.get(1).unwrap()), "Align64(2)");
+        assert_eq!(
+            format!("{:?}", vec.first().unwrap()),
+            "Example { inner: 1.0 }"
+        );
+        assert_eq!(format!("{:?}", vec.last().unwrap()), "\"three\"");
+        assert_eq!(
+            format!("{:?}", unsafe { vec.get_unchecked(2) }),
+            "\"three\""
+        );
+        assert!(vec.get(3).is_none());
+        assert!(vec.get_mut(3).is_none());
+    }
+
+    #[test]
+    fn indexed_mutation() {
+        let mut vec = PolyPtrVec::<[usize]>::new();
+        poly_push!(vec, [1; 2]);
+        poly_push!(vec, [2; 4]);
+        poly_push!(vec, [3; 1]);
+
+        vec[1][3] = 10;
+        vec.get_mut(0).unwrap()[0] = 5;
+        vec.first_mut().unwrap()[1] = 6;
+        vec.last_mut().unwrap()[0] = 7;
+        unsafe { vec.get_unchecked_mut(1)[0] = 8 };
+
+        assert_eq!(&vec[0], &[5, 6]);
+        assert_eq!(&vec[1], &[8, 2, 2, 10]);
+        assert_eq!(&vec[2], &[7]);
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds: the len is 1 but the index is 1")]
+    fn index_out_of_bounds() {
+        let mut vec = PolyPtrVec::<[usize]>::new();
+        poly_push!(vec, [1; 2]);
+        let _ = &vec[1];
+    }
+
+    trait Update: Debug {
+        fn update(&mut self, step: f64);
+    }
+
+    impl Update for Example {
+        fn update(&mut self, step: f64) {
+            self.inner += step;
+        }
+    }
+
+    impl Update for Point {
+        fn update(&mut self, step: f64) {
+            self.x += step;
+            self.z -= step;
+        }
+    }
+
+    #[test]
+    fn iter_mut_updates_in_place() {
+        let mut vec = PolyPtrVec::<dyn Update>::new();
+        poly_push!(vec, Example::new(1.0));
+        poly_push!(vec, Point::new(0.0, 0.0, 2.0));
+        poly_push!(vec, Example::new(-1.0));
+
+        vec.iter_mut().for_each(|value| value.update(0.5));
+        for value in &mut vec {
+            value.update(0.25);
+        }
+        assert_eq!(vec.iter_mut().count(), 3);
+
+        let values = vec
+            .iter()
+            .map(|value| format!("{:?}", value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [
+                "Example { inner: 1.75 }",
+                "Point { x: 0.75, y: 0.0, z: 1.25 }",
+                "Example { inner: -0.25 }"
+            ]
+        );
+    }
+
+    #[test]
+    fn double_ended_iteration() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        for value in 0..6u8 {
+            poly_push!(vec, value);
+            poly_push!(vec, Align16(value));
+        }
+
+        let mut iter = vec.iter();
+        assert_eq!(iter.len(), 12);
+        assert_eq!(format!("{:?}", iter.next_back().unwrap()), "Align16(5)");
+        assert_eq!(format!("{:?}", iter.nth(2).unwrap()), "1");
+        assert_eq!(format!("{:?}", iter.nth_back(1).unwrap()), "Align16(4)");
+        assert_eq!(iter.len(), 6);
+        assert_eq!(format!("{:?}", iter.clone().last().unwrap()), "4");
+        assert!(iter.nth(6).is_none());
+        assert!(iter.next().is_none());
+        assert!(iter.next_back().is_none());
+
+        let reversed = vec.iter().rev().skip(9).map(|value| format!("{:?}", value));
+        assert_eq!(reversed.collect::<Vec<_>>(), ["1", "Align16(0)", "0"]);
+
+        let mut count = 0;
+        for _ in &vec {
+            count += 1;
+        }
+        assert_eq!(count, vec.len());
+    }
+
+    #[test]
+    fn double_ended_iteration_mut() {
+        let mut vec = PolyPtrVec::<[usize]>::new();
+        poly_push!(vec, [0; 1]);
+        poly_push!(vec, [0; 2]);
+        poly_push!(vec, [0; 3]);
+
+        for (index, value) in vec.iter_mut().rev().enumerate() {
+            value.fill(index);
+        }
+        let mut iter = vec.iter_mut();
+        assert_eq!(iter.len(), 3);
+        iter.nth_back(0).unwrap()[0] = 7;
+        assert_eq!(iter.nth(1).unwrap(), &[1, 1]);
+        assert!(iter.next().is_none());
+
+        assert_eq!(&vec[0], &[2]);
+        assert_eq!(&vec[2], &[7, 0, 0]);
+    }
+
+    #[test]
+    fn pop_and_remove_boxed() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, String::from("first"));
+        poly_push!(vec, Align64(2));
+        poly_push!(vec, DropCounter::new(&drops, "third"));
+        poly_push!(vec, ());
+        poly_push!(vec, 4u8);
+
+        let removed = vec.remove_boxed(2);
+        assert_eq!(drops.get(), 0);
+        assert!(format!("{:?}", removed).contains("third"));
+        drop(removed);
+        assert_eq!(drops.get(), 1);
+
+        let boxed = vec.remove_boxed(1);
+        assert_eq!(format!("{:?}", boxed), "Align64(2)");
+        assert_eq!((&*boxed as *const dyn Debug).addr() % 64, 0);
+        assert_aligned(&vec);
+
+        assert_eq!(format!("{:?}", vec.pop().unwrap()), "4");
+        assert_eq!(format!("{:?}", vec.pop().unwrap()), "()");
+        assert_eq!(format!("{:?}", vec.pop().unwrap()), "\"first\"");
+        assert!(vec.pop().is_none());
+        assert!(vec.is_empty());
+    }
+
+    #[test]
+    fn swap_remove_boxed() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, Point::new(1.0, 2.0, 3.0));
+        poly_push!(vec, 1u8);
+        poly_push!(vec, Align16(2));
+        poly_push!(vec, 3u16);
+
+        // The last element fits into the bytes of the removed point
+        let removed = vec.swap_remove_boxed(0);
+        assert_eq!(format!("{:?}", removed), "Point { x: 1.0, y: 2.0, z: 3.0 }");
+        assert_eq!(names(&vec), ["3", "1", "Align16(2)"]);
+        assert_eq!(vec.storage.buf_len(), 48);
+        assert_aligned(&vec);
+
+        // The last element doesn't fit into a single byte and has to be reinserted
+        let removed = vec.swap_remove_boxed(1);
+        assert_eq!(format!("{:?}", removed), "1");
+        assert_eq!(names(&vec), ["3", "Align16(2)"]);
+        assert_aligned(&vec);
+
+        let removed = vec.swap_remove_boxed(1);
+        assert_eq!(format!("{:?}", removed), "Align16(2)");
+        assert_eq!(names(&vec), ["3"]);
+        poly_push!(vec, String::from("next"));
+        assert_eq!(names(&vec), ["3", "\"next\""]);
+    }
+
+    #[test]
+    fn swap_remove_boxed_shifts_once() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, 2u16);
+        poly_push!(vec, Align16(3));
+        poly_push!(vec, [4u8; 20]);
+        let len = vec.storage.buf_len();
+        // The array doesn't fit into the byte, and the shifted elements overlap it
+        assert_eq!(format!("{:?}", vec.swap_remove_boxed(0)), "1");
+        assert_eq!(names(&vec)[0], format!("{:?}", [4u8; 20]));
+        assert_eq!(names(&vec)[1..], ["2", "Align16(3)"]);
+        assert_aligned(&vec);
+        assert!(vec.storage.buf_len() <= len + 16);
+
+        let mut vec = PolyPtrVec::<[u8]>::new();
+        poly_push!(vec, [1u8; 1]);
+        poly_push!(vec, [2u8; 2]);
+        poly_push!(vec, [3u8; 3]);
+        poly_push!(vec, [4u8; 4]);
+        assert_eq!(&*vec.swap_remove_boxed(0), [1]);
+        assert!(vec.iter().eq([&[4u8; 4][..], &[2; 2], &[3; 3]]));
+        assert_eq!(vec.storage.buf_len(), 9);
+    }
+
+    #[test]
+    #[should_panic(expected = "removal index (is 2) should be < len (is 2)")]
+    fn swap_remove_boxed_out_of_bounds() {
+        let mut vec = PolyPtrVec::<[usize]>::new();
+        poly_push!(vec, [1; 2]);
+        poly_push!(vec, [1; 3]);
+        vec.swap_remove_boxed(2);
+    }
+
+    #[test]
+    fn into_iter_yields_boxes() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, String::from("two"));
+        poly_push!(vec, Align64(3));
+        poly_push!(vec, Point::new(4.0, 4.0, 4.0));
+
+        let mut iter = vec.into_iter();
+        assert_eq!(iter.len(), 4);
+        assert_eq!(format!("{:?}", iter.next().unwrap()), "1");
+        assert_eq!(
+            format!("{:?}", iter.next_back().unwrap()),
+            "Point { x: 4.0, y: 4.0, z: 4.0 }"
+        );
+        let rest = iter.map(|value| format!("{:?}", value)).collect::<Vec<_>>();
+        assert_eq!(rest, ["\"two\"", "Align64(3)"]);
+    }
+
+    #[test]
+    fn into_iter_drops_remaining() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        for name in ["a", "b", "c", "d", "e"] {
+            poly_push!(vec, DropCounter::new(&drops, name));
+            poly_push!(vec, String::from(name));
+        }
+
+        let mut iter = vec.into_iter();
+        let first = iter.next().unwrap();
+        let last = iter.next_back().unwrap();
+        drop(iter);
+        assert_eq!(drops.get(), 4);
+        drop(first);
+        assert_eq!(drops.get(), 5);
+        drop(last);
+        assert_eq!(drops.get(), 5);
+
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, DropCounter::new(&drops, "f"));
+        for value in vec {
+            assert!(format!("{:?}", value).contains("\"f\""));
+        }
+        assert_eq!(drops.get(), 6);
+    }
+
+    #[test]
+    fn drain_range() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 0u8);
+        poly_push!(vec, String::from("one"));
+        poly_push!(vec, Align64(2));
+        poly_push!(vec, 3u16);
+        poly_push!(vec, Align16(4));
+        poly_push!(vec, 5u64);
+
+        let drained = vec
+            .drain(1..4)
+            .map(|value| format!("{:?}", value))
+            .collect::<Vec<_>>();
+        assert_eq!(drained, ["\"one\"", "Align64(2)", "3"]);
+        assert_eq!(names(&vec), ["0", "Align16(4)", "5"]);
+        assert_aligned(&vec);
+
+        poly_push!(vec, Align64(6));
+        let mut drain = vec.drain(..=1);
+        assert_eq!(format!("{:?}", drain.next_back().unwrap()), "Align16(4)");
+        drop(drain);
+        assert_eq!(names(&vec), ["5", "Align64(6)"]);
+        assert_aligned(&vec);
+
+        assert_eq!(vec.drain(2..).count(), 0);
+        assert_eq!(vec.drain(1..1).count(), 0);
+        assert_eq!(vec.len(), 2);
+        assert_eq!(vec.drain(..).len(), 2);
+        assert!(vec.is_empty());
+        poly_push!(vec, 7u8);
+        assert_eq!(names(&vec), ["7"]);
+    }
+
+    #[test]
+    fn drain_drops_unconsumed() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        for name in ["a", "b", "c", "d"] {
+            poly_push!(vec, DropCounter::new(&drops, name));
+        }
+        poly_push!(vec, String::from("tail"));
+
+        let mut drain = vec.drain(1..4);
+        let taken = drain.next().unwrap();
+        drop(drain);
+        assert_eq!(drops.get(), 2);
+        drop(taken);
+        assert_eq!(drops.get(), 3);
+        assert_eq!(vec.len(), 2);
+        assert_eq!(format!("{:?}", &vec[1]), "\"tail\"");
+
+        vec.clear();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, 2u16);
+        poly_push!(vec, 3u32);
+        std::mem::forget(vec.drain(1..2));
+        assert_eq!(names(&vec), ["1"]);
+        poly_push!(vec, 4u64);
+        assert_eq!(names(&vec), ["1", "4"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "range end index 3 out of range for slice of length 2")]
+    fn drain_out_of_bounds() {
+        let mut vec = PolyPtrVec::<[usize]>::new();
+        poly_push!(vec, [1; 2]);
+        poly_push!(vec, [1; 3]);
+        vec.drain(1..3);
+    }
+
+    #[test]
+    fn retain_compacts() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        for round in 0..4u8 {
+            poly_push!(vec, DropCounter::new(&drops, "counter"));
+            poly_push!(vec, round);
+            poly_push!(vec, Align64(round as u16));
+            poly_push!(vec, String::from("text"));
+        }
+
+        vec.retain(|value| {
+            let name = format!("{:?}", value);
+            !name.contains("counter") && name != "1" && name != "Align64(2)"
+        });
+        assert_eq!(drops.get(), 4);
+        assert_eq!(
+            names(&vec),
+            [
+                "0",
+                "Align64(0)",
+                "\"text\"",
+                "Align64(1)",
+                "\"text\"",
+                "2",
+                "\"text\"",
+                "3",
+                "Align64(3)",
+                "\"text\""
+            ]
+        );
+        assert_aligned(&vec);
+
+        poly_push!(vec, 5u8);
+        poly_insert!(vec, 0, Align16(6));
+        assert_eq!(vec.len(), 12);
+        assert_aligned(&vec);
+        vec.retain(|_| false);
+        assert!(vec.is_empty());
+    }
+
+    #[test]
+    fn retain_mut_updates_kept() {
+        let mut vec = PolyPtrVec::<[usize]>::new();
+        poly_push!(vec, [1; 1]);
+        poly_push!(vec, [2; 2]);
+        poly_push!(vec, [3; 3]);
+        poly_push!(vec, [4; 4]);
+        poly_push!(vec, [5; 5]);
+        poly_push!(vec, [6; 6]);
+        vec.retain_mut(|value| {
+            value[0] += 10;
+            value.len() % 2 == 0
+        });
+        let values = vec.iter().map(|value| value.to_vec()).collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [vec![12, 2], vec![14, 4, 4, 4], vec![16, 6, 6, 6, 6, 6]]
+        );
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    struct PanicOnDrop(u8);
+
+    impl Drop for PanicOnDrop {
+        fn drop(&mut self) {
+            if !std::thread::panicking() {
+                panic!("dropping {}", self.0);
+            }
+        }
+    }
+
+    #[test]
+    fn retain_survives_panicking_predicate() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, DropCounter::new(&drops, "a"));
+        poly_push!(vec, Align16(2));
+        poly_push!(vec, DropCounter::new(&drops, "b"));
+        poly_push!(vec, 3u64);
+
+        let mut visited = 0;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            vec.retain(|value| {
+                visited += 1;
+                if visited == 4 {
+                    panic!("predicate");
+                }
+                !format!("{:?}", value).contains("DropCounter")
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(drops.get(), 1);
+        assert_eq!(vec.len(), 4);
+        assert_aligned(&vec);
+        assert_eq!(format!("{:?}", &vec[1]), "Align16(2)");
+        assert_eq!(format!("{:?}", &vec[3]), "3");
+        drop(vec);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn retain_survives_panicking_destructor() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, Align16(1));
+        poly_push!(vec, PanicOnDrop(2));
+        poly_push!(vec, DropCounter::new(&drops, "a"));
+        poly_push!(vec, 4u8);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            vec.retain(|value| format!("{:?}", value).starts_with("Align"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(drops.get(), 0);
+        assert_eq!(vec.len(), 3);
+        assert_aligned(&vec);
+        assert_eq!(format!("{:?}", &vec[0]), "Align16(1)");
+        assert_eq!(format!("{:?}", &vec[2]), "4");
+        drop(vec);
+        assert_eq!(drops.get(), 1);
+    }
+
+    /// Counts the allocations made through a reference to it.
+    #[derive(Default)]
+    struct CountingAlloc {
+        live: Cell<usize>,
+        total: Cell<usize>,
+    }
+
+    unsafe impl Allocator for &CountingAlloc {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.live.set(self.live.get() + 1);
+            self.total.set(self.total.get() + 1);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.live.set(self.live.get() - 1);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn inline_push_until_full() {
+        let mut vec = InlinePolyVec::<dyn Debug, 32, 8>::new();
+        for value in 0..4u64 {
+            poly_push!(vec, value).unwrap();
+        }
+        assert_eq!(poly_push!(vec, 4u64).unwrap_err(), 4);
+        assert!(poly_push!(vec, 5u8).is_err());
+        assert_eq!(vec.len(), 4);
+        assert_eq!(vec.raw_capacity(), 32);
+        assert!(vec.try_reserve::<u8>(1).is_err());
+
+        // Out of views before running out of bytes
+        let mut vec = InlinePolyVec::<dyn Debug, 64, 2>::new();
+        poly_push!(vec, 1u8).unwrap();
+        poly_insert!(vec, 0, 2u8).unwrap();
+        assert_eq!(poly_push!(vec, 3u8).unwrap_err(), 3);
+        assert_eq!(poly_insert!(vec, 1, 4u8).unwrap_err(), 4);
+        assert_eq!(names(&vec), ["2", "1"]);
+    }
+
+    #[test]
+    fn inline_push_within_capacity() {
+        let mut vec = InlinePolyVec::<dyn Debug, 64>::new();
+        push_within(&mut vec, 1u64).unwrap();
+        push_within(&mut vec, Align16(2)).unwrap();
+        let rejected = push_within(&mut vec, Align64(3));
+        assert_eq!(rejected.unwrap_err().0, 3);
+        poly_insert!(vec, 0, 4u8).unwrap();
+        assert_aligned(&vec);
+        assert_eq!(names(&vec), ["4", "1", "Align16(2)"]);
+    }
+
+    #[test]
+    fn inline_elements_stay_inside_the_vec() {
+        let mut vec = InlinePolyVec::<dyn Debug, 64>::default();
+        poly_push!(vec, 1u8).unwrap();
+        poly_push!(vec, Align16(2)).unwrap();
+        poly_insert!(vec, 1, 3u32).unwrap();
+        assert_eq!(poly_push!(vec, Align64(4)).unwrap_err().0, 4);
+        assert_aligned(&vec);
+        assert_eq!(names(&vec), ["1", "3", "Align16(2)"]);
+
+        let start = &vec as *const _ as usize;
+        let end = start + size_of::<InlinePolyVec<dyn Debug, 64>>();
+        for value in vec.iter() {
+            let addr = (value as *const dyn Debug).addr();
+            assert!(start <= addr && addr < end);
+        }
+    }
+
+    #[test]
+    fn inline_shares_vec_semantics() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = InlinePolyVec::<dyn Debug, 256>::new();
+        poly_push!(vec, DropCounter::new(&drops, "a")).unwrap();
+        poly_push!(vec, 1u16).unwrap();
+        poly_push!(vec, DropCounter::new(&drops, "b")).unwrap();
+        poly_push!(vec, Point::new(1.0, 2.0, 3.0)).unwrap();
+        poly_push!(vec, DropCounter::new(&drops, "c")).unwrap();
+        assert_eq!(format!("{:?}", &vec[1]), "1");
+        assert_eq!(vec.iter().rev().count(), 5);
+
+        vec.remove(0);
+        assert_eq!(drops.get(), 1);
+        vec.retain(|value| !format!("{:?}", value).contains("\"b\""));
+        assert_eq!(drops.get(), 2);
+        assert_eq!(names(&vec)[..2], ["1", "Point { x: 1.0, y: 2.0, z: 3.0 }"]);
+        assert_aligned(&vec);
+
+        let mut iter = vec.into_iter();
+        assert_eq!(format!("{:?}", iter.next().unwrap()), "1");
+        drop(iter);
+        assert_eq!(drops.get(), 3);
+    }
+
+    #[test]
+    fn small_spills_to_the_heap() {
+        let mut vec = SmallPolyVec::<dyn Debug, 16, 4>::new();
+        poly_push!(vec, 1u64);
+        poly_push!(vec, 2u32);
+        assert!(!vec.spilled());
+        let start = &vec as *const _ as usize;
+        let end = start + size_of::<SmallPolyVec<dyn Debug, 16, 4>>();
+        let addr = (&vec[1] as *const dyn Debug).addr();
+        assert!(start <= addr && addr < end);
+
+        poly_insert!(vec, 1, 3u64);
+        assert!(vec.spilled());
+        poly_push!(vec, Align64(4));
+        assert_aligned(&vec);
+        assert_eq!(names(&vec), ["1", "3", "2", "Align64(4)"]);
+
+        // Running out of views or alignment spills as well
+        let mut vec = SmallPolyVec::<dyn Debug, 64, 2>::default();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, 2u8);
+        assert!(!vec.spilled());
+        poly_push!(vec, 3u8);
+        assert!(vec.spilled());
+        let mut vec = SmallPolyVec::<dyn Debug, 64, 2>::new();
+        poly_push!(vec, Align64(1));
+        assert!(vec.spilled());
+        assert_aligned(&vec);
+
+        assert!(!SmallPolyVec::<dyn Debug, 64>::with_capacity::<u64>(8).spilled());
+        assert!(SmallPolyVec::<dyn Debug, 64>::with_capacity::<u64>(9).spilled());
+    }
+
+    #[test]
+    fn small_keeps_elements_across_spill() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = SmallPolyVec::<dyn Debug, 64, 2>::new();
+        poly_push!(vec, DropCounter::new(&drops, "a"));
+        poly_push!(vec, DropCounter::new(&drops, "b"));
+        assert!(!vec.spilled());
+        poly_push!(vec, DropCounter::new(&drops, "c"));
+        poly_push!(vec, Point::new(1.0, 2.0, 3.0));
+        assert!(vec.spilled());
+        assert_eq!(drops.get(), 0);
+
+        vec.remove(0);
+        assert_eq!(drops.get(), 1);
+        assert_eq!(vec.drain(..1).count(), 1);
+        assert_eq!(drops.get(), 2);
+        assert_eq!(format!("{:?}", &vec[1]), "Point { x: 1.0, y: 2.0, z: 3.0 }");
+        drop(vec);
+        assert_eq!(drops.get(), 3);
+    }
+
+    #[test]
+    fn sparse_removal_leaves_holes() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        vec.set_sparse(true);
+        poly_push!(vec, [1u8; 16]);
+        poly_push!(vec, [2u8; 16]);
+        poly_push!(vec, [3u8; 16]);
+        let addr =
+            |vec: &PolyPtrVec<dyn Debug>, index: usize| (&vec[index] as *const dyn Debug).addr();
+        let third = addr(&vec, 2);
+
+        vec.remove(0);
+        assert_eq!(vec.hole_bytes(), 16);
+        assert_eq!(addr(&vec, 1), third);
+        // A fitting value goes into the hole, but still behind the other elements
+        poly_push!(vec, 4u32);
+        poly_push!(vec, Align16(5));
+        assert_eq!(vec.hole_bytes(), 12);
+        assert!(addr(&vec, 2) < addr(&vec, 0));
+        assert_eq!(names(&vec)[2..], ["4", "Align16(5)"]);
+        assert_aligned(&vec);
+        assert_eq!(
+            format!("{:?}", vec.remove_boxed(1)),
+            "[3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3]"
+        );
+        assert_eq!(format!("{:?}", vec.pop().unwrap()), "Align16(5)");
+
+        vec.compact();
+        assert_eq!(vec.hole_bytes(), 0);
+        assert!(addr(&vec, 0) < addr(&vec, 1));
+        assert_eq!(
+            names(&vec),
+            ["[2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2]", "4"]
+        );
+        assert_aligned(&vec);
+    }
+
+    #[test]
+    fn sparse_moves_only_views() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        vec.set_sparse(true);
+        for name in ["a", "b", "c", "d", "e"] {
+            poly_push!(vec, DropCounter::new(&drops, name));
+        }
+        let addr =
+            |vec: &PolyPtrVec<dyn Debug>, index: usize| (&vec[index] as *const dyn Debug).addr();
+        let (c, e) = (addr(&vec, 2), addr(&vec, 4));
+        vec.remove(1);
+        vec.remove(2);
+        poly_push!(vec, 1u8);
+        poly_insert!(vec, 1, 2u16);
+        // Both values went into holes, none of the other elements moved
+        assert_eq!(vec.hole_bytes(), 2 * size_of::<DropCounter>() - 3);
+        assert_eq!((addr(&vec, 2), addr(&vec, 3)), (c, e));
+        assert_eq!(drops.get(), 2);
+        assert_eq!(vec.len(), 5);
+        assert_eq!(names(&vec)[1..2], ["2"]);
+        assert_eq!(names(&vec)[4..], ["1"]);
+
+        vec.remove(0);
+        vec.retain(|value| !format!("{:?}", value).contains('2'));
+        assert_eq!(drops.get(), 3);
+        assert_eq!(addr(&vec, 1), e);
+        assert_eq!(vec.drain(..1).count(), 1);
+        assert_eq!(drops.get(), 4);
+        assert_eq!(addr(&vec, 0), e);
+        poly_push!(vec, 3u32);
+        assert!(format!("{:?}", vec.swap_remove_boxed(0)).contains("\"e\""));
+        assert_eq!(names(&vec), ["3", "1"]);
+        vec.truncate(1);
+        assert_eq!(names(&vec), ["3"]);
+        vec.set_sparse(false);
+        assert!(!vec.is_sparse());
+        assert_eq!(vec.hole_bytes(), 0);
+        assert_aligned(&vec);
+        drop(vec);
+        assert_eq!(drops.get(), 5);
+    }
+
+    #[test]
+    #[should_panic = "sparse mode needs a storage that can allocate a free list"]
+    fn inline_rejects_sparse_mode() {
+        InlinePolyVec::<dyn Debug, 16>::new().set_sparse(true);
+    }
+
+    #[test]
+    fn compact_grows_for_padding() {
+        let mut vec = PolyPtrVec::<dyn Debug>::raw_with_capacity(4, 130);
+        vec.set_sparse(true);
+        poly_push!(vec, Align64(1));
+        poly_push!(vec, [2u8; 65]);
+        poly_insert!(vec, 0, 3u8);
+        assert_eq!(vec.raw_capacity(), 130);
+        // In view order the byte array needs padding it didn't need behind the `Align64`
+        vec.compact();
+        assert!(vec.raw_capacity() >= 193);
+        assert_aligned(&vec);
+        assert_eq!(names(&vec)[..2], ["3", "Align64(1)"]);
+        assert_eq!(vec.get_as::<[u8; 65]>(2), Some(&[2; 65]));
+    }
+
+    #[test]
+    fn sparse_small_vec_spills() {
+        let mut vec = SmallPolyVec::<dyn Debug, 16>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, 2u8);
+        vec.set_sparse(true);
+        assert!(vec.spilled());
+        vec.remove(0);
+        poly_push!(vec, 3u8);
+        vec.compact();
+        assert_eq!(names(&vec), ["2", "3"]);
+    }
+
+    #[test]
+    fn remove_deferred_leaves_tombstones() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, DropCounter::new(&drops, "a"));
+        poly_push!(vec, Align16(2));
+        poly_push!(vec, 3u32);
+        let addr =
+            |vec: &PolyPtrVec<dyn Debug>, index: usize| (&vec[index] as *const dyn Debug).addr();
+        let last = addr(&vec, 3);
+
+        vec.remove_deferred(1);
+        vec.remove_deferred(1);
+        assert_eq!(drops.get(), 1);
+        assert_eq!((vec.len(), vec.len_with_tombstones()), (3, 4));
+        assert_eq!(vec.tombstones(), 1);
+        assert_eq!(vec.tombstoned_bytes(), size_of::<DropCounter>());
+        assert!(vec.get(1).is_none());
+        assert_eq!(addr(&vec, 3), last);
+        assert_eq!(vec.iter().len(), 3);
+        assert_eq!(names(&vec), ["1", "Align16(2)", "3"]);
+        assert_eq!(vec.iter_mut().rev().count(), 3);
+        assert_eq!(format!("{:?}", vec.iter().nth(1).unwrap()), "Align16(2)");
+        let mut iter = vec.iter_mut();
+        assert_eq!(format!("{:?}", iter.nth_back(2).unwrap()), "1");
+        assert_eq!(iter.len(), 0);
+        assert!(iter.nth(1).is_none());
+
+        vec.remove_deferred(0);
+        assert_eq!(format!("{:?}", vec.first().unwrap()), "Align16(2)");
+        vec.compact();
+        assert_eq!(vec.len(), 2);
+        assert_eq!(vec.tombstones(), 0);
+        assert_eq!(vec.tombstoned_bytes(), 0);
+        assert_eq!(names(&vec), ["Align16(2)", "3"]);
+        assert_aligned(&vec);
+        drop(vec);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    #[should_panic = "element at index 0 was removed"]
+    fn index_tombstone() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        vec.remove_deferred(0);
+        let _ = &vec[0];
+    }
+
+    #[test]
+    fn removals_skip_tombstones() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        for name in ["a", "b", "c", "d", "e", "f"] {
+            poly_push!(vec, DropCounter::new(&drops, name));
+        }
+        vec.remove_deferred(1);
+        vec.remove_deferred(4);
+        vec.remove_deferred(5);
+        assert_eq!(drops.get(), 3);
+
+        // The trailing tombstones go first
+        assert!(format!("{:?}", vec.pop().unwrap()).contains("\"d\""));
+        assert_eq!((vec.len_with_tombstones(), vec.tombstones()), (3, 1));
+        vec.remove(1);
+        assert_eq!((vec.len(), vec.tombstones()), (2, 0));
+        assert_eq!(drops.get(), 4);
+
+        for name in ["g", "h", "i"] {
+            poly_push!(vec, DropCounter::new(&drops, name));
+        }
+        vec.remove_deferred(1);
+        assert_eq!(vec.drain(..3).len(), 2);
+        assert_eq!(drops.get(), 7);
+        assert_eq!(vec.tombstones(), 0);
+
+        poly_push!(vec, DropCounter::new(&drops, "j"));
+        poly_push!(vec, DropCounter::new(&drops, "k"));
+        vec.remove_deferred(0);
+        let mut visited = 0;
+        vec.retain(|_| {
+            visited += 1;
+            true
+        });
+        assert_eq!(visited, 3);
+        assert_eq!((vec.len(), vec.tombstones()), (3, 0));
+        vec.remove_deferred(1);
+        let mut iter = vec.into_iter();
+        assert_eq!(iter.len(), 2);
+        assert!(format!("{:?}", iter.next_back().unwrap()).contains("\"k\""));
+        assert!(format!("{:?}", iter.next().unwrap()).contains("\"i\""));
+        assert!(iter.next().is_none());
+        assert_eq!(drops.get(), 11);
+    }
+
+    #[test]
+    fn tombstones_move_with_their_elements() {
+        let drops = Rc::new(Cell::new(0));
+        let counter_names = |vec: &PolyPtrVec<dyn Debug>| -> Vec<String> {
+            vec.iter_of::<DropCounter>()
+                .map(|counter| counter.name.clone())
+                .collect()
+        };
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        for name in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+            poly_push!(vec, DropCounter::new(&drops, name));
+        }
+        vec.remove_deferred(1);
+        vec.remove_deferred(3);
+        vec.remove_deferred(6);
+        assert_eq!(drops.get(), 3);
+
+        // Only the live element in the range is yielded, the tombstones are forgotten
+        let mut drain = vec.drain(1..4);
+        assert_eq!(drain.len(), 1);
+        assert!(format!("{:?}", drain.next().unwrap()).contains("\"c\""));
+        assert!(drain.next().is_none());
+        drop(drain);
+        assert_eq!(drops.get(), 4);
+        assert_eq!((vec.len(), vec.len_with_tombstones()), (4, 5));
+        // Moving the tail down reclaims the bytes of its tombstone too
+        assert_eq!((vec.tombstones(), vec.tombstoned_bytes()), (1, 0));
+        assert!(vec.get(3).is_none());
+        assert_eq!(counter_names(&vec), ["a", "e", "f", "h"]);
+
+        // The tombstone goes with the tail, but its bytes stay behind
+        let mut tail = vec.split_off(2);
+        assert_eq!(
+            (vec.len(), vec.tombstones(), vec.tombstoned_bytes()),
+            (2, 0, 0)
+        );
+        assert_eq!((tail.len(), tail.len_with_tombstones()), (2, 3));
+        assert_eq!((tail.tombstones(), tail.tombstoned_bytes()), (1, 0));
+        assert!(tail.get(1).is_none());
+        assert_eq!(counter_names(&tail), ["f", "h"]);
+
+        // Transfers count indices including tombstones on both sides
+        tail.transfer(2, &mut vec);
+        assert_eq!((tail.len(), tail.len_with_tombstones()), (1, 2));
+        vec.remove_deferred(0);
+        tail.transfer_to_index(0, &mut vec, 1);
+        assert_eq!(
+            (vec.len(), vec.len_with_tombstones(), vec.tombstones()),
+            (3, 4, 1)
+        );
+        assert!(vec.get(0).is_none());
+        assert_eq!(counter_names(&vec), ["f", "e", "h"]);
+        assert!(tail.is_empty());
+        assert_eq!(tail.tombstones(), 1);
+        tail.compact();
+        assert_eq!(tail.len_with_tombstones(), 0);
+        assert_eq!(drops.get(), 5);
+        drop(vec);
+        assert_eq!(drops.get(), 8);
+
+        // Same in sparse mode, where the bytes of the tombstones become holes
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        vec.set_sparse(true);
+        for value in 0..6u64 {
+            poly_push!(vec, value);
+        }
+        vec.remove(1);
+        vec.remove_deferred(1);
+        drop(vec.drain(1..3));
+        assert_eq!(names(&vec), ["0", "4", "5"]);
+        assert_eq!((vec.tombstones(), vec.tombstoned_bytes()), (0, 0));
+        vec.remove_deferred(2);
+        let tail = vec.split_off(1);
+        assert_eq!((tail.len(), tail.tombstones()), (1, 1));
+        assert_eq!(vec.tombstones(), 0);
+        vec.compact();
+        assert_eq!(vec.hole_bytes(), 0);
+        assert_eq!(vec.storage.buf_len(), 8);
+    }
+
+    #[test]
+    fn sparse_keeps_tombstone_indices() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        vec.set_sparse(true);
+        for value in 0..5u64 {
+            poly_push!(vec, value);
+        }
+        vec.remove(1);
+        vec.remove_deferred(2);
+        assert_eq!(vec.tombstoned_bytes(), 8);
+        // Inserting takes the hole without moving the tombstone
+        poly_insert!(vec, 0, 9u64);
+        assert_eq!(vec.hole_bytes(), 0);
+        assert_eq!(vec.tombstoned_bytes(), 8);
+        assert_eq!(vec.tombstones(), 1);
+        assert!(vec.get(3).is_none());
+        assert_eq!(names(&vec), ["9", "0", "2", "4"]);
+        vec.remove(3);
+        assert_eq!((vec.hole_bytes(), vec.tombstoned_bytes()), (8, 0));
+        vec.compact();
+        assert_eq!(names(&vec), ["9", "0", "2", "4"]);
+        assert_eq!(vec.len(), 4);
+        assert_aligned(&vec);
+    }
+
+    #[test]
+    fn downcast_elements() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, String::from("two"));
+        poly_push!(vec, DropCounter::new(&drops, "three"));
+        poly_insert!(vec, 0, Align16(4));
+        assert!(vec.is::<Align16>(0));
+        assert!(vec.is::<u8>(1));
+        assert!(!vec.is::<u16>(1));
+        assert!(!vec.is::<u8>(4));
+        assert_eq!(vec.get_as::<String>(2).unwrap(), "two");
+        assert!(vec.get_as::<&str>(2).is_none());
+        vec.get_as_mut::<String>(2).unwrap().push('!');
+        assert_eq!(format!("{:?}", &vec[2]), "\"two!\"");
+
+        assert!(vec.remove_as::<u8>(0).is_none());
+        assert_eq!(vec.len(), 4);
+        let counter = vec.remove_as::<DropCounter>(3).unwrap();
+        assert_eq!(counter.name, "three");
+        assert_eq!(drops.get(), 0);
+        drop(counter);
+        assert_eq!(drops.get(), 1);
+        assert_eq!(vec.remove_as::<String>(2).unwrap(), "two!");
+        assert_eq!(names(&vec), ["Align16(4)", "1"]);
+
+        vec.remove_deferred(1);
+        assert!(!vec.is::<u8>(1));
+        assert!(vec.remove_as::<u8>(1).is_none());
+        // The type survives elements being moved around
+        poly_push!(vec, 5u64);
+        vec.swap_remove_boxed(0);
+        assert_eq!(vec.get_as::<u64>(0), Some(&5));
+        vec.set_sparse(true);
+        poly_push!(vec, 6u32);
+        assert_eq!(vec.remove_as::<u64>(0), Some(5));
+        assert_eq!(vec.get_as::<u32>(1), Some(&6));
+    }
+
+    #[test]
+    fn downcast_rejects_other_types() {
+        #[allow(dead_code)]
+        #[derive(Debug)]
+        struct Meters(u32);
+
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u32);
+        poly_push!(vec, vec![2u8]);
+        poly_push!(vec, DropCounter::new(&drops, "three"));
+        poly_push!(vec, ());
+        let addresses: Vec<*const dyn Debug> = vec.iter().map(|value| value as *const _).collect();
+
+        // Same size and alignment, or the same generic type with other parameters
+        assert!(vec.get_as::<i32>(0).is_none());
+        assert!(vec.get_as::<f32>(0).is_none());
+        assert!(vec.get_as::<[u8; 4]>(0).is_none());
+        assert!(vec.get_as::<Meters>(0).is_none());
+        assert!(vec.get_as_mut::<Vec<i8>>(1).is_none());
+        assert!(vec.get_as::<[(); 0]>(3).is_none());
+        assert!(vec.get_as::<u32>(4).is_none());
+        assert!(vec.get_as_mut::<u32>(4).is_none());
+
+        assert!(vec.remove_as::<i32>(0).is_none());
+        assert!(vec.remove_as::<Vec<i8>>(1).is_none());
+        assert!(vec.remove_as::<String>(2).is_none());
+        assert!(vec.remove_as::<[(); 0]>(3).is_none());
+        assert_eq!(drops.get(), 0);
+        assert_eq!(vec.get_as::<u32>(0), Some(&1));
+        assert_eq!(vec.get_as::<Vec<u8>>(1), Some(&vec![2]));
+        assert_eq!(vec.get_as::<DropCounter>(2).unwrap().name, "three");
+        assert_eq!(vec.get_as::<()>(3), Some(&()));
+        assert!(vec
+            .iter()
+            .map(|value| value as *const dyn Debug)
+            .eq(addresses));
+
+        // Nothing moved out, so the value is still dropped with the vec
+        drop(vec);
+        assert_eq!(drops.get(), 1);
+
+        let mut vec = InlinePolyVec::<dyn Debug, 32>::new();
+        poly_push!(vec, 1u16).unwrap();
+        assert!(vec.remove_as::<i16>(0).is_none());
+        assert_eq!(vec.remove_as::<u16>(0), Some(1));
+    }
+
+    #[test]
+    #[should_panic = "removal index (is 1) should be < len (is 1)"]
+    fn remove_as_out_of_bounds() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        vec.remove_as::<u16>(1);
+    }
+
+    #[test]
+    fn iterate_by_type() {
+        #[derive(Debug, PartialEq)]
+        struct Click(u32);
+        #[derive(Debug, PartialEq)]
+        struct Key(char);
+
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, Click(1));
+        poly_push!(vec, Key('a'));
+        poly_push!(vec, Click(2));
+        poly_push!(vec, Key('b'));
+        poly_push!(vec, Click(3));
+        vec.remove_deferred(2);
+        assert_eq!(vec.count_of::<Click>(), 2);
+        assert_eq!(vec.count_of::<u8>(), 0);
+        assert_eq!(vec.first_of::<Key>(), Some(&Key('a')));
+        assert!(vec.iter_of::<Click>().eq([&Click(1), &Click(3)]));
+        assert_eq!(vec.iter_of::<Key>().next_back(), Some(&Key('b')));
+        for click in vec.iter_of_mut::<Click>().rev() {
+            click.0 *= 10;
+        }
+        assert!(vec.iter_of::<Click>().eq([&Click(10), &Click(30)]));
+
+        assert_eq!(vec.remove_all_of::<Click>(), 2);
+        assert_eq!(vec.len(), 2);
+        assert_eq!(vec.tombstones(), 0);
+        assert_eq!(names(&vec), ["Key('a')", "Key('b')"]);
+        assert!(vec.first_of::<Click>().is_none());
+    }
+
+    #[test]
+    fn type_filters_skip_tombstones() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        for name in ["a", "b", "c", "d"] {
+            poly_push!(vec, DropCounter::new(&drops, name));
+            poly_push!(vec, name.len() as u8);
+        }
+        assert_eq!(vec.count_of::<DropCounter>(), 4);
+        vec.remove_deferred(0);
+        vec.remove_deferred(4);
+        vec.remove_deferred(5);
+        assert_eq!(drops.get(), 2);
+        assert_eq!(vec.count_of::<DropCounter>(), 2);
+        assert_eq!(vec.count_of::<u8>(), 3);
+        assert_eq!(vec.first_of::<DropCounter>().unwrap().name, "b");
+        assert!(vec
+            .iter_of::<DropCounter>()
+            .map(|counter| counter.name.as_str())
+            .eq(["b", "d"]));
+        // Compacting reclaims the tombstones without changing what is counted
+        vec.compact();
+        assert_eq!(vec.count_of::<DropCounter>(), 2);
+        assert_eq!(vec.count_of::<u8>(), 3);
+
+        vec.remove_deferred(0);
+        vec.remove_deferred(1);
+        assert_eq!(drops.get(), 3);
+        assert_eq!(vec.tombstones(), 2);
+        // Tombstones are neither counted nor dropped again, but they are removed
+        assert_eq!(vec.remove_all_of::<DropCounter>(), 1);
+        assert_eq!(drops.get(), 4);
+        assert_eq!(vec.tombstones(), 0);
+        assert_eq!(vec.tombstoned_bytes(), 0);
+        assert_eq!(names(&vec), ["1", "1"]);
+        assert_eq!(vec.len_with_tombstones(), 2);
+        assert_eq!(vec.remove_all_of::<DropCounter>(), 0);
+
+        // Tombstones of other types go as well, and sparse vecs keep their holes
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        vec.set_sparse(true);
+        for value in 0..6u32 {
+            poly_push!(vec, value);
+            poly_push!(vec, Align16(value as u8));
+        }
+        vec.remove_deferred(1);
+        vec.remove_deferred(2);
+        assert_eq!(vec.remove_all_of::<u32>(), 5);
+        assert_eq!(vec.tombstones(), 0);
+        assert_eq!(vec.count_of::<Align16>(), 5);
+        assert_eq!(vec.len(), 5);
+        assert_aligned(&vec);
+        poly_push!(vec, 7u32);
+        assert_eq!(vec.count_of::<u32>(), 1);
+        vec.compact();
+        assert_eq!(vec.hole_bytes(), 0);
+        assert_eq!(vec.count_of::<Align16>(), 5);
+    }
+
+    trait Snapshot: PolyClone + Debug {}
+
+    impl<U: Clone + Debug> Snapshot for U {}
+
+    #[test]
+    fn clone_copies_elements() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Snapshot>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, Align64(2));
+        poly_push!(vec, String::from("three"));
+        poly_push!(vec, Rc::clone(&drops));
+        poly_push!(vec, [4u16; 3]);
+        vec.remove_deferred(0);
+
+        let mut copy = vec.clone();
+        assert_eq!(Rc::strong_count(&drops), 3);
+        assert_eq!(copy.len_with_tombstones(), 5);
+        assert_eq!(copy.tombstones(), 1);
+        assert_eq!(
+            format!("{:?}", copy.iter().collect::<Vec<_>>()),
+            format!("{:?}", vec.iter().collect::<Vec<_>>())
+        );
+        assert_eq!((&copy[1] as *const dyn Snapshot).addr() % 64, 0);
+        assert!(!ptr::eq(
+            &copy[2] as *const dyn Snapshot as *const u8,
+            &vec[2] as *const dyn Snapshot as *const u8
+        ));
+        copy.remove(2);
+        assert_eq!(format!("{:?}", &vec[2]), "\"three\"");
+        drop(vec);
+        drop(copy);
+        assert_eq!(Rc::strong_count(&drops), 1);
+
+        let empty = PolyPtrVec::<dyn Snapshot>::new();
+        assert!(empty.clone().is_empty());
+    }
+
+    #[test]
+    fn clone_survives_panicking_clone() {
+        #[derive(Debug)]
+        struct Fragile;
+
+        impl Clone for Fragile {
+            fn clone(&self) -> Self {
+                panic!("cannot clone");
+            }
+        }
+
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Snapshot>::new();
+        poly_push!(vec, Rc::clone(&drops));
+        poly_push!(vec, Fragile);
+        poly_push!(vec, Rc::clone(&drops));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vec.clone()));
+        assert!(result.is_err());
+        // Only the clone made before the panic existed, and it was dropped again
+        assert_eq!(Rc::strong_count(&drops), 3);
+    }
+
+    #[test]
+    fn extend_from_vec_copies_once() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        unsafe { vec.extend_from_vec_coerce(vec![Align16(2), Align16(3)], |value| value) };
+        unsafe { vec.extend_from_vec_coerce(Vec::<u64>::new(), |value| value) };
+        let counters = ["a", "b", "c"].map(|name| DropCounter::new(&drops, name));
+        unsafe { vec.extend_from_vec_coerce(counters.into(), |value| value) };
+        unsafe { vec.extend_from_vec_coerce(vec![(); 2], |value| value) };
+        assert_eq!(vec.len(), 8);
+        assert_eq!(names(&vec)[..3], ["1", "Align16(2)", "Align16(3)"]);
+        assert_eq!(names(&vec)[6..], ["()", "()"]);
+        assert!(vec.is::<DropCounter>(4));
+        assert_aligned(&vec);
+        assert_eq!(drops.get(), 0);
+        vec.remove(3);
+        assert_eq!(drops.get(), 1);
+        drop(vec);
+        assert_eq!(drops.get(), 3);
+
+        let mut vec = PolyPtrVec::<[u8]>::new();
+        unsafe { vec.extend_from_vec_coerce(vec![[1u8, 2], [3, 4]], |value| value) };
+        assert!(vec.iter().eq([&[1u8, 2][..], &[3, 4]]));
+    }
+
+    #[test]
+    fn extend_on_stable() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_extend!(vec, (0..3u16).map(|value| value as u8));
+        poly_extend!(vec, [Align64(3), Align64(4)]);
+        poly_extend!(vec, ["a", "b"].map(|name| DropCounter::new(&drops, name)));
+        assert_eq!(
+            names(&vec)[..5],
+            ["0", "1", "2", "Align64(3)", "Align64(4)"]
+        );
+        assert_aligned(&vec);
+        drop(vec);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn extend_and_collect() {
+        let mut vec: PolyPtrVec<dyn Debug> = (0..3u16).collect();
+        vec.extend(["three", "four"]);
+        vec.extend_from_vec(vec![5u32]);
+        assert_eq!(names(&vec), ["0", "1", "2", "\"three\"", "\"four\"", "5"]);
+        assert_aligned(&vec);
+    }
+
+    #[test]
+    fn append_moves_all_elements() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, DropCounter::new(&drops, "a"));
+        let mut other = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(other, Align64(2));
+        poly_push!(other, DropCounter::new(&drops, "b"));
+        poly_push!(other, 3u16);
+        other.remove_deferred(1);
+        assert_eq!(drops.get(), 1);
+
+        vec.append(&mut other);
+        assert!(other.is_empty());
+        assert_eq!(vec.len_with_tombstones(), 5);
+        assert_eq!(vec.tombstones(), 1);
+        assert_eq!(names(&vec)[2..], ["Align64(2)", "3"]);
+        assert!(vec.is::<u16>(4));
+        assert_aligned(&vec);
+        vec.append(&mut other);
+        assert_eq!(vec.len_with_tombstones(), 5);
+
+        // `other` stays usable
+        poly_push!(other, 4u32);
+        vec.append(&mut other);
+        assert_eq!(names(&vec).last().unwrap(), "4");
+        drop(vec);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn split_off_moves_the_tail() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, Align16(2));
+        poly_push!(vec, String::from("three"));
+        poly_push!(vec, 4u64);
+        vec.remove_deferred(3);
+
+        let mut tail = vec.split_off(2);
+        assert_eq!(names(&vec), ["1", "Align16(2)"]);
+        assert_eq!(names(&tail), ["\"three\""]);
+        assert_eq!((vec.tombstones(), tail.tombstones()), (0, 1));
+        assert_eq!((tail.len(), tail.len_with_tombstones()), (1, 2));
+        assert_aligned(&tail);
+        assert!(vec.split_off(2).is_empty());
+
+        let head = vec.split_off(0);
+        assert!(vec.is_empty());
+        assert_eq!(names(&head), ["1", "Align16(2)"]);
+        assert_aligned(&head);
+        tail.compact();
+        assert_eq!(tail.len_with_tombstones(), 1);
+    }
+
+    #[test]
+    fn split_off_drops_leading_padding() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, Align64(0));
+        for byte in 0..64u8 {
+            poly_push!(vec, byte);
+        }
+        // The last byte sits 127 bytes into a buffer aligned to 64
+        let tail = vec.split_off(64);
+        assert_eq!(names(&tail), ["63"]);
+        assert_eq!(tail.raw_capacity(), 1);
+        assert_eq!(vec.len(), 64);
+        assert_aligned(&vec);
+    }
+
+    #[test]
+    #[should_panic = "`at` split index (is 2) should be <= len (is 1)"]
+    fn split_off_out_of_bounds() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        let _ = vec.split_off(2);
+    }
+
+    #[test]
+    fn transfer_between_vecs() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        poly_push!(vec, Align64(2));
+        poly_push!(vec, DropCounter::new(&drops, "a"));
+        let mut dst = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(dst, 3u16);
+
+        vec.transfer(1, &mut dst);
+        vec.transfer_to_index(1, &mut dst, 0);
+        assert_eq!(names(&vec), ["1"]);
+        assert_eq!(dst.len(), 3);
+        assert_eq!(names(&dst)[1..], ["3", "Align64(2)"]);
+        assert!(dst.is::<DropCounter>(0));
+        assert_aligned(&dst);
+        assert_eq!(drops.get(), 0);
+
+        dst.set_sparse(true);
+        dst.transfer(2, &mut vec);
+        assert_eq!(dst.hole_bytes(), 0);
+        assert_eq!(names(&vec), ["1", "Align64(2)"]);
+        assert_aligned(&vec);
+        drop(vec);
+        drop(dst);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    #[should_panic = "element at index 0 was removed"]
+    fn transfer_tombstone() {
+        let mut vec = PolyPtrVec::<dyn Debug>::new();
+        poly_push!(vec, 1u8);
+        vec.remove_deferred(0);
+        vec.transfer(0, &mut PolyPtrVec::new());
+    }
+
+    #[test]
+    fn custom_allocator() {
+        let alloc = CountingAlloc::default();
+        {
+            let mut vec = PolyPtrVec::<dyn Debug, _>::new_in(&alloc);
+            assert_eq!(alloc.total.get(), 0);
+            poly_push!(vec, 1u8);
+            poly_push!(vec, Align64(2));
+            poly_push!(vec, String::from("three"));
+            poly_insert!(vec, 1, Point::new(4.0, 5.0, 6.0));
+            vec.raw_reserve(1024);
+            // The view table and the byte buffer
+            assert_eq!(alloc.live.get(), 2);
+            assert!(alloc.total.get() > 2);
+            assert_aligned(&vec);
+            assert_eq!(
+                names(&vec),
+                [
+                    "1",
+                    "Point { x: 4.0, y: 5.0, z: 6.0 }",
+                    "Align64(2)",
+                    "\"three\""
+                ]
+            );
+            vec.shrink_to_fit();
+            assert_eq!(alloc.live.get(), 2);
+            assert_eq!(format!("{:?}", vec.pop().unwrap()), "\"three\"");
+        }
+        assert_eq!(alloc.live.get(), 0);
+
+        let vec = PolyPtrVec::<[u8], _>::with_capacity_in::<[u8; 4]>(8, &alloc);
+        assert_eq!(alloc.live.get(), 2);
+        assert!(vec.capacity::<[u8; 4]>() >= 8);
+        drop(vec);
+        let vec = PolyPtrVec::<[u8], _>::raw_with_capacity_in(0, 64, &alloc);
+        assert_eq!(alloc.live.get(), 1);
+        assert_eq!(vec.raw_capacity(), 64);
+        drop(vec);
+        assert_eq!(alloc.live.get(), 0);
+    }
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn standard_allocator() {
+        let mut vec = PolyPtrVec::<dyn Debug, _>::new_in(std::alloc::System);
+        poly_push!(vec, 1u8);
+        poly_push!(vec, Align64(2));
+        assert_aligned(&vec);
+        let mut vec = PolyPtrVec::<dyn Debug, _>::new_in(&std::alloc::System);
+        poly_push!(vec, String::from("one"));
+        assert_eq!(names(&vec), ["\"one\""]);
+    }
+
+    #[test]
+    fn sparse_mode_allocates_in_the_storage() {
+        let alloc = CountingAlloc::default();
+        let mut vec = PolyPtrVec::<dyn Debug, _>::new_in(&alloc);
+        vec.set_sparse(true);
+        for value in 0..4u64 {
+            poly_push!(vec, value);
+        }
+        let live = alloc.live.get();
+        vec.remove(0);
+        vec.remove(1);
+        // The table of size classes and the class of both holes
+        assert_eq!(alloc.live.get(), live + 2);
+        poly_push!(vec, 4u64);
+        let total = alloc.total.get();
+        vec.compact();
+        // The temporary copy of the bytes is released again
+        assert_eq!(alloc.total.get(), total + 1);
+        assert_eq!(alloc.live.get(), live + 2);
+        assert_eq!(names(&vec), ["1", "3", "4"]);
+        vec.set_sparse(false);
+        assert_eq!(alloc.live.get(), live);
+        drop(vec);
+        assert_eq!(alloc.live.get(), 0);
     }
 }